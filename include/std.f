@@ -21,6 +21,9 @@
 \ Print spaces.
 : space  ( -- )  32 emit ;
 : spaces  ( n -- )  0 do space loop ;
+
+\ Print the string.
+: type  ( c-addr u -- )  0 do dup @ emit 1+ loop drop ;
 \ Space (decimal 32)
 : bl ( -- n ) 32 ;
 
@@ -1,12 +1,19 @@
 use crate::{
+    clock::to_date_time,
     errors::Error::{
-        self, Abort, DivisionByZero, Exit, InvalidAddress, Leave, Quit, StackUnderflow,
+        self, Abort, Bye, CustomError, DivisionByZero, Exit, Leave, Quit, StackUnderflow,
     },
     expressions::Expr::{self, Callable, Dummy, Value},
-    forth::Forth,
+    forth::{Forth, ARGUMENTS, INPUT_BUFFER, TO_IN, WORD_BUFFER},
     numbers::{from_bool, is_true, saturating_i64_to_i32, to_char, Int, FALSE, TRUE},
+    random::Random,
 };
-use std::cmp::Ordering;
+use std::time::Duration;
+
+/// The ior codes returned by the memory-allocation words, as in the standard's `throw` codes.
+const ALLOCATE_ERROR: Int = -59;
+const FREE_ERROR: Int = -60;
+const RESIZE_ERROR: Int = -61;
 
 const BUILDINS: &[(&str, Expr)] = &[
     // logic
@@ -62,12 +69,34 @@ const BUILDINS: &[(&str, Expr)] = &[
     ("allot", Callable(allot)),
     ("here", Callable(here)),
     (",", Callable(store)),
+    ("allocate", Callable(allocate)),
+    ("free", Callable(free)),
+    ("resize", Callable(resize)),
     // i/o
     ("cr", Callable(cr)),
     (".", Callable(dot)),
     ("char", Dummy),
     ("emit", Callable(emit)),
     (".r", Callable(right_align)),
+    // parsing
+    ("source", Callable(source)),
+    (">in", Value(TO_IN)),
+    ("parse", Callable(parse)),
+    ("parse-name", Callable(parse_name)),
+    ("word", Callable(word)),
+    ("refill", Callable(refill)),
+    // command-line arguments
+    ("argc", Callable(argc)),
+    ("arg", Callable(arg)),
+    // time
+    ("ms", Callable(ms)),
+    ("time&date", Callable(time_and_date)),
+    ("utime", Callable(utime)),
+    ("ticks", Callable(ticks)),
+    // random numbers
+    ("random", Callable(random)),
+    ("choose", Callable(choose)),
+    ("seed", Callable(seed)),
     // compile-only words and the words handled specially by parser
     ("if", Dummy),
     ("then", Dummy),
@@ -77,6 +106,8 @@ const BUILDINS: &[(&str, Expr)] = &[
     (".(", Dummy),
     (".\"", Dummy),
     ("include", Dummy),
+    ("save-image", Dummy),
+    ("load-image", Dummy),
     ("to", Dummy),
     // looping
     ("while", Callable(while_cond)),
@@ -91,12 +122,32 @@ const BUILDINS: &[(&str, Expr)] = &[
     ("words", Callable(words)),
     ("see", Dummy),
     ("bye", Callable(bye)),
+    ("(bye)", Callable(bye_with_code)),
     ("exit", Callable(exit)),
     ("quit", Callable(quit)),
     ("leave", Callable(leave)),
     ("abort", Callable(abort)),
+    ("abort\"", Dummy),
 ];
 
+/// The name of the builtin function, used to refer to it in the saved images.
+pub(crate) fn buildin_name(func: fn(&mut Forth) -> Result<(), Error>) -> Option<&'static str> {
+    BUILDINS.iter().find_map(|(name, expr)| match expr {
+        Callable(other) if std::ptr::fn_addr_eq(*other, func) => Some(*name),
+        _ => None,
+    })
+}
+
+/// The definition of the builtin word.
+pub(crate) fn buildin(name: &str) -> Option<&'static Expr> {
+    BUILDINS
+        .iter()
+        .find_map(|(key, expr)| (*key == name).then_some(expr))
+}
+
+/// The standard library, embedded in the binary.
+const PRELUDE: &str = include_str!("../include/std.f");
+
 impl Forth {
     /// Constructs a new, empty Forth server with the stack with at least the specified capacity and
     /// a dictionary of predefined words.
@@ -107,8 +158,26 @@ impl Forth {
                 .define_word(key, val.clone())
                 .expect("there should be no duplicate definitions");
         }
+        forth.buildins = forth.slots.len();
+        forth
+    }
+
+    /// Constructs a new Forth server like `new`, with the words from the standard
+    /// library (`include/std.f`) defined on top of the builtins.
+    pub fn with_prelude(capacity: usize) -> Self {
+        let mut forth = Forth::new(capacity);
+        forth
+            .load_prelude()
+            .expect("the prelude should load without errors");
         forth
     }
+
+    /// Define the words from the standard library (`include/std.f`). They are compiled
+    /// using the current configuration, e.g. `set_optimize`. It fails if some of the words
+    /// are already defined or the limits are too low, then the interpreter is not changed.
+    pub fn load_prelude(&mut self) -> Result<(), Error> {
+        self.eval_string_atomic(PRELUDE)
+    }
 }
 
 /// `+ ( n1 n2 -- sum )`
@@ -386,32 +455,33 @@ fn clearstack(forth: &mut Forth) -> Result<(), Error> {
 
 /// `cr ( -- )`
 /// Print newline.
-fn cr(_: &mut Forth) -> Result<(), Error> {
-    println!();
-    Ok(())
+fn cr(forth: &mut Forth) -> Result<(), Error> {
+    forth.print("\n")
 }
 
 /// `. ( n -- )`
 /// Take the value from the top of the stack and print it followed by space.
 fn dot(forth: &mut Forth) -> Result<(), Error> {
-    print!("{} ", forth.stack_pop()?);
-    Ok(())
+    let value = forth.stack_pop()?;
+    forth.print(&format!("{} ", value))
 }
 
 /// `emit ( n -- )`
 /// Take the value from the top of the stack and print it as a character.
 fn emit(forth: &mut Forth) -> Result<(), Error> {
     let val = forth.stack_pop()?;
-    print!("{}", to_char(val));
-    Ok(())
+    forth.print(&to_char(val).to_string())
 }
 
 /// `.r ( n u -- )`
 /// Display integer n right-aligned in a field u spaces wide.
 fn right_align(forth: &mut Forth) -> Result<(), Error> {
     let (value, width) = forth.stack_pop2()?;
-    print!("{value:>width$}", value = value, width = width as usize);
-    Ok(())
+    forth.print(&format!(
+        "{value:>width$}",
+        value = value,
+        width = width as usize
+    ))
 }
 
 /// `.s ( -- )`
@@ -427,36 +497,29 @@ fn print_stack(forth: &mut Forth) -> Result<(), Error> {
         .join(" ");
     let n = forth.stack_len();
     let dots = if n > show_max { "..." } else { "" };
-    print!(" <{}> {}{}", n, stack, dots);
-    Ok(())
+    forth.print(&format!(" <{}> {}{}", n, stack, dots))
 }
 
 /// `words ( -- )`
 /// Print all the available words.
 fn words(forth: &mut Forth) -> Result<(), Error> {
-    print!("{}", forth.words().join(" "));
-    Ok(())
+    let words = forth.words().join(" ");
+    forth.print(&words)
 }
 
 /// `! ( n addr -- )`
 /// Set the the variable at addr to n.
 fn set(forth: &mut Forth) -> Result<(), Error> {
     let (val, addr) = forth.stack_pop2()?;
-    let addr = addr as usize;
-    match addr.cmp(&forth.memory.len()) {
-        Ordering::Greater => return Err(InvalidAddress),
-        Ordering::Equal => forth.memory.push(val),
-        Ordering::Less => forth.memory[addr] = val,
-    }
-    Ok(())
+    forth.write_memory(addr, val)
 }
 
 /// `@ ( addr -- n )`
 /// Get the value of the variable at addr.
 fn fetch(forth: &mut Forth) -> Result<(), Error> {
-    let addr = forth.stack_pop()? as usize;
-    let val = forth.memory.get(addr).ok_or(InvalidAddress)?;
-    forth.stack_push(*val);
+    let addr = forth.stack_pop()?;
+    let val = forth.read_memory(addr)?;
+    forth.stack_push(val);
     Ok(())
 }
 
@@ -464,29 +527,18 @@ fn fetch(forth: &mut Forth) -> Result<(), Error> {
 /// Print count cells at the memory address addr.
 fn dump(forth: &mut Forth) -> Result<(), Error> {
     let (start, count) = forth.stack_pop2()?;
-    let start = start as usize;
-    let end = start + count as usize;
-    if end > forth.memory.len() {
-        return Err(InvalidAddress);
-    }
-    print!(
-        "{}",
-        &forth.memory[start..end]
-            .iter()
-            .map(|x| x.to_string())
-            .collect::<Vec<_>>()
-            .join(" ")
-    );
-    Ok(())
+    let cells = (start..start.saturating_add(count))
+        .map(|addr| forth.read_memory(addr).map(|x| x.to_string()))
+        .collect::<Result<Vec<_>, Error>>()?;
+    forth.print(&cells.join(" "))
 }
 
 /// `allot ( count -- )`
 /// Allocate count number of memory cells.
 fn allot(forth: &mut Forth) -> Result<(), Error> {
-    let count = forth.stack_pop()?;
-    for _ in 0..count {
-        forth.memory.push(0);
-    }
+    let count = usize::try_from(forth.stack_pop()?).unwrap_or(0);
+    forth.check_memory(count)?;
+    forth.memory.resize(forth.memory.len() + count, 0);
     Ok(())
 }
 
@@ -494,6 +546,7 @@ fn allot(forth: &mut Forth) -> Result<(), Error> {
 /// Store value in memory.
 fn store(forth: &mut Forth) -> Result<(), Error> {
     let value = forth.stack_pop()?;
+    forth.check_memory(1)?;
     forth.memory.push(value);
     Ok(())
 }
@@ -506,6 +559,202 @@ fn here(forth: &mut Forth) -> Result<(), Error> {
     Ok(())
 }
 
+/// `source ( -- c-addr u )`
+/// The address and length of the input buffer.
+fn source(forth: &mut Forth) -> Result<(), Error> {
+    let len = forth.parser.source().len() as Int;
+    forth.stack_push(INPUT_BUFFER);
+    forth.stack_push(len);
+    Ok(())
+}
+
+/// `parse ( char "ccc<char>" -- c-addr u )`
+/// Parse the input delimited by the character.
+fn parse(forth: &mut Forth) -> Result<(), Error> {
+    let delimiter = to_char(forth.stack_pop()?);
+    let (start, len) = forth.parser.parse(delimiter);
+    forth.stack_push(INPUT_BUFFER + start as Int);
+    forth.stack_push(len as Int);
+    Ok(())
+}
+
+/// `parse-name ( "<spaces>name<space>" -- c-addr u )`
+/// Skip leading whitespaces and parse the name delimited by a whitespace.
+fn parse_name(forth: &mut Forth) -> Result<(), Error> {
+    let (start, len) = forth.parser.parse_name();
+    forth.stack_push(INPUT_BUFFER + start as Int);
+    forth.stack_push(len as Int);
+    Ok(())
+}
+
+/// `word ( char "<chars>ccc<char>" -- c-addr )`
+/// Skip leading delimiters, parse the input delimited by the character and store it
+/// as a counted string in the transient buffer.
+fn word(forth: &mut Forth) -> Result<(), Error> {
+    let delimiter = to_char(forth.stack_pop()?);
+    let (start, len) = forth.parser.word(delimiter);
+    let chars = &forth.parser.source()[start..start + len];
+    forth.word_buffer = std::iter::once(len as Int)
+        .chain(chars.iter().map(|c| *c as Int))
+        .collect();
+    forth.stack_push(WORD_BUFFER);
+    Ok(())
+}
+
+/// `argc ( -- n )`
+/// The number of the command-line arguments.
+fn argc(forth: &mut Forth) -> Result<(), Error> {
+    forth.stack_push(forth.arguments.len() as Int);
+    Ok(())
+}
+
+/// `arg ( n -- c-addr u )`
+/// The address and length of the n-th command-line argument, `0 0` if there is none.
+fn arg(forth: &mut Forth) -> Result<(), Error> {
+    let n = usize::try_from(forth.stack_pop()?).unwrap_or(usize::MAX);
+    let (addr, len) = if n < forth.arguments.len() {
+        // the arguments are stored one after another
+        let start: usize = forth.arguments[..n]
+            .iter()
+            .map(|arg| arg.chars().count())
+            .sum();
+        let len = forth.arguments[n].chars().count();
+        (ARGUMENTS + start as Int, len as Int)
+    } else {
+        (0, 0)
+    };
+    forth.stack_push(addr);
+    forth.stack_push(len);
+    Ok(())
+}
+
+/// `refill ( -- flag )`
+/// Move to the next line of the input, return false if there is none.
+fn refill(forth: &mut Forth) -> Result<(), Error> {
+    let flag = forth.parser.refill();
+    forth.stack_push(from_bool(flag));
+    Ok(())
+}
+
+/// `allocate ( u -- a-addr ior )`
+/// Allocate u cells on the heap, ior is zero on success.
+fn allocate(forth: &mut Forth) -> Result<(), Error> {
+    let size = forth.stack_pop()?;
+    if let Ok(size) = usize::try_from(size) {
+        forth.check_memory(size)?;
+    }
+    let addr = usize::try_from(size)
+        .ok()
+        .and_then(|size| forth.heap.allocate(size));
+    match addr {
+        Some(addr) => {
+            forth.stack_push(addr);
+            forth.stack_push(0);
+        }
+        None => {
+            forth.stack_push(0);
+            forth.stack_push(ALLOCATE_ERROR);
+        }
+    }
+    Ok(())
+}
+
+/// `free ( a-addr -- ior )`
+/// Free the memory allocated on the heap, ior is zero on success.
+fn free(forth: &mut Forth) -> Result<(), Error> {
+    let addr = forth.stack_pop()?;
+    let ior = if forth.heap.free(addr) { 0 } else { FREE_ERROR };
+    forth.stack_push(ior);
+    Ok(())
+}
+
+/// `resize ( a-addr1 u -- a-addr2 ior )`
+/// Change the size of the memory allocated on the heap, the content is preserved, but it may be moved.
+/// On failure, the original address is returned and ior is non-zero.
+fn resize(forth: &mut Forth) -> Result<(), Error> {
+    let (addr, size) = forth.stack_pop2()?;
+    if let (Ok(size), Some(old)) = (usize::try_from(size), forth.heap.block_size(addr)) {
+        forth.check_memory(size.saturating_sub(old))?;
+    }
+    let new = usize::try_from(size)
+        .ok()
+        .and_then(|size| forth.heap.resize(addr, size));
+    match new {
+        Some(new) => {
+            forth.stack_push(new);
+            forth.stack_push(0);
+        }
+        None => {
+            forth.stack_push(addr);
+            forth.stack_push(RESIZE_ERROR);
+        }
+    }
+    Ok(())
+}
+
+/// `ms ( u -- )`
+/// Wait at least u milliseconds.
+fn ms(forth: &mut Forth) -> Result<(), Error> {
+    let millis = forth.stack_pop()?.max(0) as u64;
+    forth.clock.sleep(Duration::from_millis(millis));
+    Ok(())
+}
+
+/// `time&date ( -- +n1 +n2 +n3 +n4 +n5 +n6 )`
+/// The current UTC time and date as second, minute, hour, day, month, and year.
+fn time_and_date(forth: &mut Forth) -> Result<(), Error> {
+    let (second, minute, hour, day, month, year) = to_date_time(forth.clock.now());
+    for value in [second, minute, hour, day, month, year] {
+        forth.stack_push(value as Int);
+    }
+    Ok(())
+}
+
+/// `utime ( -- ud )`
+/// Microseconds since the Unix epoch as a double-cell number.
+fn utime(forth: &mut Forth) -> Result<(), Error> {
+    let micros = forth.clock.now().as_micros() as u64;
+    forth.stack_push(micros as u32 as Int);
+    forth.stack_push((micros >> 32) as Int);
+    Ok(())
+}
+
+/// `ticks ( -- n )`
+/// Milliseconds elapsed since the clock was started.
+fn ticks(forth: &mut Forth) -> Result<(), Error> {
+    let millis = forth.clock.elapsed().as_millis();
+    forth.stack_push(millis as Int);
+    Ok(())
+}
+
+/// `random ( -- n )`
+/// Pseudo-random number.
+fn random(forth: &mut Forth) -> Result<(), Error> {
+    let value = forth.random.next_u32();
+    forth.stack_push(value as Int);
+    Ok(())
+}
+
+/// `choose ( n -- 0..n-1 )`
+/// Pseudo-random number in the `0..n` range.
+fn choose(forth: &mut Forth) -> Result<(), Error> {
+    let n = forth.stack_pop()?;
+    if n <= 0 {
+        return Err(CustomError("choose needs a positive number".into()));
+    }
+    let value = forth.random.below(n as u32);
+    forth.stack_push(value as Int);
+    Ok(())
+}
+
+/// `seed ( n -- )`
+/// Seed the pseudo-random number generator, the same seed gives the same sequence of numbers.
+fn seed(forth: &mut Forth) -> Result<(), Error> {
+    let seed = forth.stack_pop()?;
+    forth.random = Random::new(seed as u32 as u64);
+    Ok(())
+}
+
 /// `while ( n -- )`
 /// If flag is false, break the loop.
 fn while_cond(forth: &mut Forth) -> Result<(), Error> {
@@ -572,13 +821,19 @@ fn loop_j(forth: &mut Forth) -> Result<(), Error> {
 /// `bye ( -- )`
 /// Exit Forth.
 fn bye(_: &mut Forth) -> Result<(), Error> {
-    std::process::exit(0);
+    Err(Bye(0))
+}
+
+/// `(bye) ( n -- )`
+/// Exit Forth with the exit code n.
+fn bye_with_code(forth: &mut Forth) -> Result<(), Error> {
+    let code = forth.stack_pop()?;
+    Err(Bye(code))
 }
 
 /// `leave ( -- )`
 /// Break the loop.
-fn leave(forth: &mut Forth) -> Result<(), Error> {
-    forth.return_stack.clear();
+fn leave(_: &mut Forth) -> Result<(), Error> {
     Err(Leave)
 }
 
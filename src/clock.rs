@@ -0,0 +1,84 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The source of time used by the interpreter.
+pub trait Clock {
+    /// The current time, as the duration since the Unix epoch.
+    fn now(&self) -> Duration;
+    /// The time elapsed since the clock was created.
+    fn elapsed(&self) -> Duration;
+    /// Wait for the duration.
+    fn sleep(&mut self, duration: Duration);
+}
+
+/// The clock using the system time that really sleeps.
+pub struct SystemClock(Instant);
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self(Instant::now())
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.0.elapsed()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+}
+
+/// The deterministic clock where the time moves only when sleeping.
+pub struct VirtualClock {
+    start: Duration,
+    now: Duration,
+}
+
+impl VirtualClock {
+    /// Create the clock starting at the time given as the duration since the Unix epoch.
+    pub fn new(now: Duration) -> Self {
+        Self { start: now, now }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        self.now
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.now - self.start
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        self.now += duration;
+    }
+}
+
+/// Transform the time since the Unix epoch to the UTC date and time
+/// `(second, minute, hour, day, month, year)`.
+pub(crate) fn to_date_time(since_epoch: Duration) -> (u64, u64, u64, u64, u64, u64) {
+    let secs = since_epoch.as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let (hour, minute, second) = (secs / 3600, secs % 3600 / 60, secs % 60);
+
+    // See: http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    (second, minute, hour, day, month, year)
+}
@@ -0,0 +1,164 @@
+use crate::{
+    expressions::Expr,
+    forth::Forth,
+    instructions::Instr::{self, *},
+    numbers::Int,
+    optimizer::optimize,
+};
+use std::{rc::Rc, str::FromStr};
+
+/// Compile the expressions to the flat list of instructions with jumps. The words are bound
+/// to the dictionary slots and the numbers are parsed at compile time.
+pub fn compile(forth: &mut Forth, body: &[Expr]) -> Vec<Instr> {
+    let mut compiler = Compiler {
+        forth,
+        code: Vec::new(),
+        depth: 0,
+    };
+    compiler.compile_many(body);
+    compiler.code
+}
+
+/// Compile the body of the function, return the code that is executed and the code as it was
+/// compiled, before optimizing. The calls in the tail position are marked as tail calls.
+pub fn compile_function(forth: &mut Forth, body: &[Expr]) -> (Rc<[Instr]>, Rc<[Instr]>) {
+    let code: Rc<[Instr]> = mark_tail_calls(compile(forth, body)).into();
+    if !forth.optimize {
+        return (code.clone(), code);
+    }
+    let optimized = mark_tail_calls(optimize(forth, &code));
+    (optimized.into(), code)
+}
+
+/// Replace the calls in the tail position with the tail calls.
+fn mark_tail_calls(mut code: Vec<Instr>) -> Vec<Instr> {
+    // the exits of the loops enclosing the instruction
+    let mut loops = Vec::new();
+    for i in 0..code.len() {
+        while loops.last().is_some_and(|&exit| exit <= i) {
+            loops.pop();
+        }
+        match code[i] {
+            Begin(exit) | Do(exit) => loops.push(exit),
+            Call(slot) if loops.is_empty() && is_tail(&code, i + 1) => code[i] = TailCall(slot),
+            _ => (),
+        }
+    }
+    code
+}
+
+/// Check if the execution starting at the index reaches the end of the function
+/// without executing any other instructions. The calls inside the loops are never
+/// in the tail position, even when followed by `exit`, because the called word
+/// can use the loop, e.g. with `i` or `leave`.
+#[inline]
+fn is_tail(code: &[Instr], mut index: usize) -> bool {
+    loop {
+        match code.get(index) {
+            None | Some(Exit) => return true,
+            Some(Else(target)) => index = *target,
+            Some(_) => return false,
+        }
+    }
+}
+
+struct Compiler<'a> {
+    /// The interpreter holding the dictionary.
+    forth: &'a mut Forth,
+    /// The compiled code.
+    code: Vec<Instr>,
+    /// Number of loops enclosing the currently compiled expression.
+    depth: usize,
+}
+
+impl Compiler<'_> {
+    /// Compile all the expressions in `body`.
+    fn compile_many(&mut self, body: &[Expr]) {
+        for expr in body {
+            self.compile(expr);
+        }
+    }
+
+    /// Compile the expression.
+    fn compile(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Word(word) => match word.as_str() {
+                "exit" => self.code.push(Exit),
+                "leave" if self.depth > 0 => self.code.push(Leave),
+                _ => {
+                    let instr = self.bind(word);
+                    self.code.push(instr);
+                }
+            },
+            Expr::Value(value) | Expr::Char(value) => self.code.push(Push(*value)),
+            Expr::IfElseThen(then, other) => {
+                let start = self.reserve();
+                self.compile_many(then);
+                if other.is_empty() {
+                    self.code[start] = If(self.code.len());
+                } else {
+                    let middle = self.reserve();
+                    self.code[start] = If(self.code.len());
+                    self.compile_many(other);
+                    self.code[middle] = Else(self.code.len());
+                }
+            }
+            Expr::Begin(body) => {
+                // the parser keeps `until` as the last word of the body
+                let (body, until) = match body.split_last() {
+                    Some((Expr::Word(word), rest)) if word == "until" => (rest, true),
+                    _ => (body.as_slice(), false),
+                };
+                let start = self.reserve();
+                let mut repeat = false;
+                self.depth += 1;
+                for expr in body {
+                    match expr {
+                        Expr::Word(word) if word == "while" => {
+                            self.code.push(While);
+                            repeat = true;
+                        }
+                        expr => self.compile(expr),
+                    }
+                }
+                self.depth -= 1;
+                let end = if until {
+                    Until(start + 1)
+                } else if repeat {
+                    Repeat(start + 1)
+                } else {
+                    Again(start + 1)
+                };
+                self.code.push(end);
+                self.code[start] = Begin(self.code.len());
+            }
+            Expr::Loop(body) => {
+                let start = self.reserve();
+                self.depth += 1;
+                self.compile_many(body);
+                self.depth -= 1;
+                self.code.push(Loop(start + 1));
+                self.code[start] = Do(self.code.len());
+            }
+            expr => self.code.push(Instr::Expr(expr.clone())),
+        }
+    }
+
+    /// Bind the word to its slot in the dictionary, or to the value if it is a number.
+    #[inline]
+    fn bind(&mut self, word: &str) -> Instr {
+        if !self.forth.dictionary.contains_key(word) {
+            if let Ok(num) = Int::from_str(word) {
+                return Push(num);
+            }
+        }
+        Call(self.forth.word_slot(word))
+    }
+
+    /// Reserve the place for the instruction that is filled later, return its index.
+    #[inline]
+    fn reserve(&mut self) -> usize {
+        self.code.push(Exit);
+        self.code.len() - 1
+    }
+}
@@ -1,21 +1,128 @@
+use crate::{numbers::Int, reader::Location};
 use std::fmt::Display;
 
+/// The maximal number of the words kept in the call chain of the error.
+const MAX_CALL_CHAIN: usize = 16;
+
 #[derive(Debug, PartialEq)]
 pub enum Error {
     StackUnderflow,
+    /// The data stack exceeded its maximal depth.
+    StackOverflow,
+    ReturnStackOverflow,
+    /// The memory exceeded its maximal number of cells.
+    MemoryLimitExceeded,
+    /// The limit of the executed instructions was reached.
+    OutOfFuel,
+    /// The execution was interrupted using the handle, see `Forth::interrupt_handle`.
+    Interrupted,
     UnknownWord(String),
     Redefined(String),
     InvalidAddress,
     DivisionByZero,
     CompileTimeWord,
     CustomError(String),
+    /// The interpreter image cannot be loaded.
+    InvalidImage(String),
     ParsingError(String),
+    /// The input ended before the expression was closed, e.g. a definition missing `;`.
+    IncompleteInput(String),
     InvalidName(String),
     MissingArgument,
     Leave,
     Exit,
     Quit,
     Abort,
+    /// Abort with the message, see `abort"`.
+    AbortWithMessage(String),
+    /// Terminate the interpreter with the exit code.
+    Bye(Int),
+    /// The error with the location in the source code where it happened
+    /// and the words that were executed, starting with the innermost one.
+    Traced {
+        error: Box<Error>,
+        location: Option<Location>,
+        call_chain: Vec<String>,
+    },
+}
+
+impl Error {
+    /// The error without the location and the call chain.
+    pub fn kind(&self) -> &Error {
+        match self {
+            Error::Traced { error, .. } => error,
+            error => error,
+        }
+    }
+
+    /// The error without the location and the call chain.
+    pub fn into_kind(self) -> Error {
+        match self {
+            Error::Traced { error, .. } => *error,
+            error => error,
+        }
+    }
+
+    /// The location in the source code where the error happened.
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Error::Traced { location, .. } => location.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// The words that were executed when the error happened, starting with the innermost one.
+    /// The functions that were inlined by the optimizer or replaced by tail calls are not included.
+    pub fn call_chain(&self) -> &[String] {
+        match self {
+            Error::Traced { call_chain, .. } => call_chain,
+            _ => &[],
+        }
+    }
+
+    /// Extend the call chain with the words calling the ones already in the chain.
+    /// The errors used for the control flow are not traced.
+    pub(crate) fn called_from(self, words: impl IntoIterator<Item = String>) -> Error {
+        use Error::*;
+        match self {
+            Exit | Quit | Leave | Bye(_) => self,
+            Traced {
+                error,
+                location,
+                mut call_chain,
+            } => {
+                let space = MAX_CALL_CHAIN.saturating_sub(call_chain.len());
+                call_chain.extend(words.into_iter().take(space));
+                Traced {
+                    error,
+                    location,
+                    call_chain,
+                }
+            }
+            error => Traced {
+                error: Box::new(error),
+                location: None,
+                call_chain: words.into_iter().take(MAX_CALL_CHAIN).collect(),
+            },
+        }
+    }
+
+    /// Set the location of the error, unless it is already known.
+    /// The errors used for the control flow are not traced.
+    pub(crate) fn at(self, location: Location) -> Error {
+        match self.called_from([]) {
+            Error::Traced {
+                error,
+                location: None,
+                call_chain,
+            } => Error::Traced {
+                error,
+                location: Some(location),
+                call_chain,
+            },
+            other => other,
+        }
+    }
 }
 
 impl Display for Error {
@@ -23,6 +130,11 @@ impl Display for Error {
         use Error::*;
         let msg = match self {
             StackUnderflow => "stack underflow".into(),
+            StackOverflow => "stack overflow".into(),
+            ReturnStackOverflow => "return stack overflow".into(),
+            MemoryLimitExceeded => "memory limit exceeded".into(),
+            OutOfFuel => "instruction limit exceeded".into(),
+            Interrupted => "interrupted".into(),
             UnknownWord(word) => format!("{} is an unknown word", word),
             Redefined(name) => format!("{} was redefined", name),
             InvalidAddress => "invalid memory address".into(),
@@ -31,8 +143,25 @@ impl Display for Error {
             InvalidName(name) => format!("{} is an invalid name", name),
             MissingArgument => "argument is missing".into(),
             CustomError(msg) => msg.into(),
-            ParsingError(msg) => msg.into(),
+            InvalidImage(msg) => format!("invalid image: {}", msg),
+            ParsingError(msg) | IncompleteInput(msg) => msg.into(),
             Abort => "abort".into(),
+            AbortWithMessage(msg) => msg.into(),
+            Bye(code) => format!("exit with code {}", code),
+            Traced {
+                error,
+                location,
+                call_chain,
+            } => {
+                let mut msg = match location {
+                    Some(location) => format!("{}: {}", location, error),
+                    None => error.to_string(),
+                };
+                for word in call_chain {
+                    msg += &format!("\n  in {}", word);
+                }
+                msg
+            }
             Exit | Quit | Leave => unreachable!(),
         };
         write!(f, "{}", msg)
@@ -1,34 +1,37 @@
-use std::{fmt::Display, str::FromStr};
+use std::{cell::RefCell, fmt::Display, rc::Rc, str::FromStr};
 
 use crate::{
-    errors::Error::{self, CompileTimeWord, Exit, InvalidName, Leave, UnknownWord},
+    compiler::{compile, compile_function},
+    errors::Error::{
+        self, AbortWithMessage, CompileTimeWord, CustomError, Exit, InvalidName, Redefined,
+        UnknownWord,
+    },
     forth::Forth,
+    instructions::{decompile, run, Instr},
     numbers::{is_true, Int},
 };
 
-macro_rules! maybe_break_loop {
-    ( $expr:expr ) => {
-        match $expr {
-            Err(Leave) => break,
-            result => result?,
-        }
-    };
-}
-
+/// The expression read by the parser, also used as the definition of a word in the dictionary.
 #[derive(Clone, PartialEq, Debug)]
+#[allow(unpredictable_function_pointer_comparisons)]
 pub enum Expr {
     /// Execute the function related to this word.
     Word(String),
     /// The string that is printed,
     Print(String),
+    /// Abort with the message if the flag is true.
+    AbortQuote(String),
     /// Push the character code to the stack.
     Char(Int),
     /// A builtin function.
     Callable(fn(forth: &mut Forth) -> Result<(), Error>),
+    /// A function defined by the host, see `Forth::define_native`.
+    Native(Native),
     /// Initialize a function and name it.
     NewFunction(String, Vec<Expr>),
-    /// A function that can be executed.
-    Function(Vec<Expr>),
+    /// A compiled function that can be executed, the code is shared between the copies.
+    /// The second code is the one before optimizing, used by `see`.
+    Function(Rc<[Instr]>, Rc<[Instr]>),
     /// If-then-else block.
     IfElseThen(Vec<Expr>, Vec<Expr>),
     /// Begin loop
@@ -51,6 +54,10 @@ pub enum Expr {
     Include(String),
     /// Display the content of the word.
     See(String),
+    /// Save the interpreter image to the path.
+    SaveImage(String),
+    /// Load the interpreter image from the path.
+    LoadImage(String),
     /// Placeholder for a reserved word.
     Dummy,
 }
@@ -60,47 +67,27 @@ impl Expr {
     pub fn execute(&self, forth: &mut Forth) -> Result<(), Error> {
         use Expr::*;
         match self {
-            Word(word) => match forth.get_word(word) {
-                Some(compiled) => compiled.execute(forth),
-                None => {
-                    if let Ok(num) = Int::from_str(word) {
-                        forth.stack_push(num);
-                        Ok(())
-                    } else {
-                        Err(UnknownWord(word.into()))
-                    }
-                }
-            },
+            Word(word) => execute_word(forth, word),
             Callable(exec) => exec(forth),
-            NewFunction(name, func) => {
-                let func = Function(func.clone());
-                forth.define_word(name, func)
+            Native(func) => func.call(forth),
+            NewFunction(name, body) => {
+                let redefined = forth.get_word(name).is_some();
+                // reserve the slot before compiling, so that the function can call itself
+                let slot = forth.reserve_word(name);
+                let (code, source) = compile_function(forth, body);
+                forth.slots[slot].1 = Some(Function(code, source));
+                if redefined {
+                    return Err(Redefined(name.into()));
+                }
+                Ok(())
             }
-            Function(body) => match execute_many(forth, body) {
+            Function(code, _) => match run(forth, None, code.clone()) {
                 Err(Exit) => Ok(()),
                 other => other,
             },
-            IfElseThen(then, other) => {
-                if is_true(forth.stack_pop()?) {
-                    execute_many(forth, then)
-                } else {
-                    execute_many(forth, other)
-                }
-            }
-            Begin(body) => {
-                loop {
-                    maybe_break_loop!(execute_many(forth, body))
-                }
-                Ok(())
-            }
-            Loop(body) => {
-                let (limit, index) = forth.stack_pop2()?;
-                for i in index..limit {
-                    forth.return_stack.push(i);
-                    maybe_break_loop!(execute_many(forth, body));
-                    forth.return_stack.pop();
-                }
-                Ok(())
+            IfElseThen(..) | Begin(_) | Loop(_) => {
+                let code = compile(forth, std::slice::from_ref(self));
+                run(forth, None, code.into())
             }
             NewConstant(name) => {
                 let value = forth.stack_pop()?;
@@ -111,6 +98,7 @@ impl Expr {
                 Ok(())
             }
             NewVariable(name) => {
+                forth.check_memory(1)?;
                 forth.memory.push(0);
                 let addr = forth.memory.len() - 1;
                 forth.define_word(name, Value(addr as Int))?;
@@ -128,45 +116,113 @@ impl Expr {
             }
             ToValue(name) => {
                 let value = forth.stack_pop()?;
-                match forth.dictionary.get_mut(name) {
-                    Some(Value(val)) => {
+                let slot = forth.dictionary.get(name).copied();
+                match slot.map(|slot| &mut forth.slots[slot].1) {
+                    Some(Some(Value(val))) => {
                         *val = value;
                         Ok(())
                     }
-                    Some(_) => Err(InvalidName(name.into())),
-                    None => Err(UnknownWord(name.into())),
+                    Some(Some(_)) => Err(InvalidName(name.into())),
+                    _ => Err(UnknownWord(name.into())),
                 }
             }
             Include(path) => forth.eval_file(path),
+            SaveImage(path) => forth.save_image(path),
+            LoadImage(path) => forth.load_image(path),
             Char(value) => {
                 forth.stack_push(*value);
                 Ok(())
             }
-            Print(string) => {
-                print!("{}", string);
+            Print(string) => forth.print(string),
+            AbortQuote(msg) => {
+                if is_true(forth.stack_pop()?) {
+                    forth.return_stack.clear();
+                    return Err(AbortWithMessage(msg.into()));
+                }
                 Ok(())
             }
             See(word) => {
-                match forth.dictionary.get(word) {
-                    Some(Dummy) => print!("<special word: {}>", word),
-                    Some(func @ Function(_)) => print!(": {} {} ;", word, func),
-                    Some(other) => print!("{}", other),
+                let text = match forth.get_word(word) {
+                    Some(Dummy) => format!("<special word: {}>", word),
+                    Some(Function(code, source)) => {
+                        let (source, code) = (decompile(forth, &source), decompile(forth, &code));
+                        let mut text = format!(": {} {} ;", word, source);
+                        if code != source {
+                            text += &format!("\n\\ optimized: {}", code);
+                        }
+                        text
+                    }
+                    Some(other) => other.to_string(),
                     None => return Err(UnknownWord(word.into())),
-                }
-                Ok(())
+                };
+                forth.print(&text)
             }
             Dummy => Err(CompileTimeWord),
         }
     }
 }
 
-/// Execute all the expressions in `body`.
+/// The Rust closure used as a word. Unlike the builtins, it can capture state.
+#[derive(Clone)]
+pub struct Native(Rc<RefCell<NativeFn>>);
+
+type NativeFn = dyn FnMut(&mut Forth) -> Result<(), Error>;
+
+impl Native {
+    /// Wrap the closure, so it can be used as the definition of a word.
+    pub fn new(func: impl FnMut(&mut Forth) -> Result<(), Error> + 'static) -> Self {
+        Self(Rc::new(RefCell::new(func)))
+    }
+
+    /// Call the closure, it cannot call itself while it is running.
+    #[inline]
+    fn call(&self, forth: &mut Forth) -> Result<(), Error> {
+        match self.0.try_borrow_mut() {
+            Ok(mut func) => func(forth),
+            Err(_) => Err(CustomError("native function called itself".into())),
+        }
+    }
+}
+
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl std::fmt::Debug for Native {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Native({:p})", Rc::as_ptr(&self.0))
+    }
+}
+
+/// Execute the function related to the word, or push it to the stack if it is a number.
+#[inline]
+pub(crate) fn execute_word(forth: &mut Forth, word: &str) -> Result<(), Error> {
+    match forth.dictionary.get(word) {
+        Some(&slot) => execute_slot(forth, slot),
+        None => {
+            if let Ok(num) = Int::from_str(word) {
+                forth.stack_push(num);
+                Ok(())
+            } else {
+                Err(UnknownWord(word.into()))
+            }
+        }
+    }
+}
+
+/// Execute the definition in the dictionary slot.
 #[inline]
-fn execute_many(forth: &mut Forth, body: &[Expr]) -> Result<(), Error> {
-    for obj in body {
-        obj.execute(forth)?;
+pub(crate) fn execute_slot(forth: &mut Forth, slot: usize) -> Result<(), Error> {
+    match &forth.slots[slot] {
+        (_, Some(Expr::Function(code, _))) => match run(forth, Some(slot), code.clone()) {
+            Err(Exit) => Ok(()),
+            other => other,
+        },
+        (_, Some(compiled)) => compiled.clone().execute(forth),
+        (name, None) => Err(UnknownWord(name.clone())),
     }
-    Ok(())
 }
 
 impl Display for Expr {
@@ -185,9 +241,11 @@ impl Display for Expr {
             Word(string) => string.into(),
             Char(value) => format!("char {}", value),
             Print(string) => format!(".\" {}\"", string),
+            AbortQuote(msg) => format!("abort\" {}\"", msg),
             Callable(obj) => format!("<func: {:?}>", &obj),
+            Native(_) => "<native function>".into(),
             NewFunction(name, body) => format!(": {} {} ;", name, vec_to_string(body)),
-            Function(body) => vec_to_string(body),
+            Function(code, _) => format!("<function: {} instructions>", code.len()),
             IfElseThen(then, other) => {
                 if other.is_empty() {
                     format!("if {} then", vec_to_string(then))
@@ -208,6 +266,8 @@ impl Display for Expr {
             NewValue(name) => format!("value {}", name),
             Include(path) => format!("include {}", path),
             See(word) => format!("see {}", word),
+            SaveImage(path) => format!("save-image {}", path),
+            LoadImage(path) => format!("load-image {}", path),
             ToValue(name) => format!("to {}", name),
             Dummy => unreachable!(),
         };
@@ -1,10 +1,64 @@
 use crate::{
-    errors::Error::{self, CustomError, Exit, Leave, Quit, Redefined, StackUnderflow},
-    expressions::Expr,
+    clock::{Clock, SystemClock},
+    errors::Error::{
+        self, Bye, CustomError, Exit, Interrupted, InvalidAddress, Leave, MemoryLimitExceeded,
+        OutOfFuel, Quit, Redefined, ReturnStackOverflow, StackOverflow, StackUnderflow,
+        UnknownWord,
+    },
+    expressions::{execute_slot, Expr, Native},
+    heap::Heap,
+    image,
+    instructions::{is_valid_expr, Frame},
     numbers::Int,
     parser::Parser,
+    random::Random,
 };
-use std::{collections::HashMap, fs};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fs,
+    io::{self, Read, Write},
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc,
+    },
+};
+
+/// The default maximal depth of nested function calls.
+pub(crate) const MAX_CALL_DEPTH: usize = 100_000;
+
+/// Address where the heap region used by `allocate` starts.
+pub(crate) const HEAP: Int = 0x2000_0000;
+/// Address of the first character of the input buffer, see `source`.
+pub(crate) const INPUT_BUFFER: Int = 0x4000_0000;
+/// Address of the first character of the command-line arguments, see `arg`.
+pub(crate) const ARGUMENTS: Int = 0x4800_0000;
+/// Address of the transient buffer holding the counted string parsed by `word`.
+pub(crate) const WORD_BUFFER: Int = 0x5000_0000;
+/// Address of the `>in` variable holding the position in the input buffer.
+pub(crate) const TO_IN: Int = 0x6000_0000;
+
+/// What happens with the data stack when evaluating the code fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ErrorRecovery {
+    /// Remove all the values from the stack.
+    #[default]
+    Clear,
+    /// Keep the stack as it was when the error happened.
+    Preserve,
+    /// Restore the stack as it was before evaluating the code.
+    Restore,
+}
+
+/// The state of the interpreter that can be restored, see `Forth::eval_string_atomic`.
+struct Snapshot {
+    data_stack: Vec<Int>,
+    return_stack: Vec<Int>,
+    dictionary: HashMap<String, usize>,
+    slots: Vec<(String, Option<Expr>)>,
+    memory: Vec<Int>,
+    heap: Heap,
+}
 
 /// The Forth interpreter that walks over the code and executes it.
 pub struct Forth {
@@ -12,10 +66,52 @@ pub struct Forth {
     pub data_stack: Vec<Int>,
     /// The additional temporary memory.
     pub(crate) return_stack: Vec<Int>,
-    /// Dictionary mapping words to functions, constants, etc.
-    pub(crate) dictionary: HashMap<String, Expr>,
+    /// Dictionary mapping words to the slots holding their definitions.
+    pub(crate) dictionary: HashMap<String, usize>,
+    /// The names and the definitions (functions, constants, etc.) of the words. The words
+    /// used before being defined have empty definitions. The slots are never removed,
+    /// so the compiled code can refer to them.
+    pub(crate) slots: Vec<(String, Option<Expr>)>,
+    /// The callers of the currently executed functions.
+    pub(crate) frames: Vec<Frame>,
+    /// The maximal depth of nested function calls.
+    pub(crate) max_call_depth: usize,
+    /// The maximal depth of the data stack.
+    pub(crate) max_stack_depth: usize,
+    /// The maximal depth of the return stack.
+    pub(crate) max_return_stack_depth: usize,
+    /// The maximal number of memory cells, including the heap.
+    pub(crate) max_memory: usize,
+    /// Number of the instructions that can still be executed, unlimited if `None`.
+    pub(crate) fuel: Option<u64>,
+    /// The flag set from outside to interrupt the execution.
+    pub(crate) interrupt: Arc<AtomicBool>,
+    /// What happens with the data stack on errors.
+    pub(crate) error_recovery: ErrorRecovery,
+    /// Number of the slots holding the builtin words, the optimizer relies on their behavior.
+    pub(crate) buildins: usize,
+    /// Whether the compiled functions are optimized.
+    pub(crate) optimize: bool,
+    /// Number of the compiled functions that are currently running.
+    pub(crate) running: usize,
     /// Memory for storing data related to named variables.
     pub(crate) memory: Vec<Int>,
+    /// Memory for the dynamically allocated blocks.
+    pub(crate) heap: Heap,
+    /// The parser reading the currently evaluated input.
+    pub(crate) parser: Parser,
+    /// The counted string parsed by `word`.
+    pub(crate) word_buffer: Vec<Int>,
+    /// The command-line arguments available to the scripts, see `arg`.
+    pub(crate) arguments: Vec<String>,
+    /// The source of time.
+    pub(crate) clock: Box<dyn Clock>,
+    /// The pseudo-random number generator.
+    pub(crate) random: Random,
+    /// Where the printed text goes.
+    pub(crate) output: Box<dyn Write>,
+    /// The buffer collecting the printed text instead of the output, see `eval_string_captured`.
+    pub(crate) captured: Option<Vec<u8>>,
 }
 
 impl Forth {
@@ -25,16 +121,179 @@ impl Forth {
             data_stack: Vec::with_capacity(capacity),
             return_stack: Vec::new(),
             dictionary: HashMap::new(),
+            slots: Vec::new(),
+            frames: Vec::new(),
+            max_call_depth: MAX_CALL_DEPTH,
+            max_stack_depth: usize::MAX,
+            max_return_stack_depth: usize::MAX,
+            max_memory: usize::MAX,
+            fuel: None,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            error_recovery: ErrorRecovery::default(),
+            buildins: 0,
+            optimize: true,
+            running: 0,
             memory: Vec::new(),
+            heap: Heap::default(),
+            parser: Parser::default(),
+            word_buffer: Vec::new(),
+            arguments: Vec::new(),
+            clock: Box::new(SystemClock::default()),
+            random: Random::default(),
+            output: Box::new(io::stdout()),
+            captured: None,
+        }
+    }
+
+    /// Set the maximal depth of nested function calls, deeper calls fail with `ReturnStackOverflow`.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// Set the maximal depth of the data stack, exceeding it fails with `StackOverflow`.
+    pub fn set_max_stack_depth(&mut self, depth: usize) {
+        self.max_stack_depth = depth;
+    }
+
+    /// Set the maximal depth of the return stack, exceeding it fails with `ReturnStackOverflow`.
+    pub fn set_max_return_stack_depth(&mut self, depth: usize) {
+        self.max_return_stack_depth = depth;
+    }
+
+    /// Set the maximal number of memory cells, including the ones allocated on the heap,
+    /// exceeding it fails with `MemoryLimitExceeded`.
+    pub fn set_max_memory(&mut self, cells: usize) {
+        self.max_memory = cells;
+    }
+
+    /// Set the number of instructions that can be executed, when they run out, the execution
+    /// fails with `OutOfFuel`. `None` means no limit.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Number of instructions that can still be executed, `None` if there is no limit.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Set what happens with the data stack when evaluating the code fails, by default it is cleared.
+    /// The return stack is always restored as it was before evaluating the code.
+    pub fn set_error_recovery(&mut self, policy: ErrorRecovery) {
+        self.error_recovery = policy;
+    }
+
+    /// The handle for interrupting the execution, e.g. from another thread or a signal handler.
+    /// Setting it to `true` makes the running code fail with `Interrupted` at the next loop
+    /// iteration or function call, then the flag is cleared.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    /// Enable or disable optimizing the functions defined later on.
+    pub fn set_optimize(&mut self, enabled: bool) {
+        self.optimize = enabled;
+    }
+
+    /// Replace the clock used by the time-related words, e.g. with the `VirtualClock` for testing.
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Replace the output where the printed text goes, stdout by default.
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+
+    /// Set the command-line arguments the scripts can read with `argc` and `arg`,
+    /// by convention the first one is the name of the script.
+    pub fn set_arguments(&mut self, args: impl IntoIterator<Item = String>) {
+        self.arguments = args.into_iter().collect();
+    }
+
+    /// Evaluate a string and return the text it printed, together with the result.
+    pub fn eval_string_captured(&mut self, code: &str) -> (Result<(), Error>, String) {
+        let outer = self.captured.replace(Vec::new());
+        let result = self.eval_string(code);
+        let captured = std::mem::replace(&mut self.captured, outer).unwrap_or_default();
+        (result, String::from_utf8_lossy(&captured).into_owned())
+    }
+
+    /// Print the text to the output.
+    pub(crate) fn print(&mut self, text: &str) -> Result<(), Error> {
+        match &mut self.captured {
+            Some(buffer) => buffer.extend_from_slice(text.as_bytes()),
+            None => self
+                .output
+                .write_all(text.as_bytes())
+                .map_err(|msg| CustomError(msg.to_string()))?,
         }
+        Ok(())
     }
 
     /// Evaluate a string.
     pub fn eval_string(&mut self, code: &str) -> Result<(), Error> {
-        let mut parser = Parser::from(code);
-        while let Some(result) = self.eval_next(&mut parser) {
+        self.eval_parser(Parser::from(code))
+    }
+
+    /// Evaluate a string, if it fails, restore the stacks, the dictionary, and the memory
+    /// as they were before. The state captured by the native functions is not restored.
+    pub fn eval_string_atomic(&mut self, code: &str) -> Result<(), Error> {
+        self.atomic(|forth| forth.eval_string(code))
+    }
+
+    /// Evaluate a file, if it fails, restore the state of the interpreter, see `eval_string_atomic`.
+    pub fn eval_file_atomic(&mut self, path: &str) -> Result<(), Error> {
+        self.atomic(|forth| forth.eval_file(path))
+    }
+
+    /// Run the evaluation, if it fails, restore the state of the interpreter as it was before.
+    /// Terminating with `bye` is not a failure, so the state is kept.
+    fn atomic(&mut self, eval: impl FnOnce(&mut Self) -> Result<(), Error>) -> Result<(), Error> {
+        let snapshot = Snapshot {
+            data_stack: self.data_stack.clone(),
+            return_stack: self.return_stack.clone(),
+            dictionary: self.dictionary.clone(),
+            slots: self.slots.clone(),
+            memory: self.memory.clone(),
+            heap: self.heap.clone(),
+        };
+        let result = eval(self);
+        if result.as_ref().is_err_and(|err| !matches!(err, Bye(_))) {
+            self.data_stack = snapshot.data_stack;
+            self.return_stack = snapshot.return_stack;
+            self.dictionary = snapshot.dictionary;
+            self.slots = snapshot.slots;
+            self.memory = snapshot.memory;
+            self.heap = snapshot.heap;
+        }
+        result
+    }
+
+    /// Evaluate the input read by the parser.
+    fn eval_parser(&mut self, parser: Parser) -> Result<(), Error> {
+        // the outer input is restored after evaluating the string, e.g. for `include`
+        let outer = std::mem::replace(&mut self.parser, parser);
+        let result = self.eval_input();
+        self.parser = outer;
+        result
+    }
+
+    /// Evaluate the current input.
+    fn eval_input(&mut self) -> Result<(), Error> {
+        // the outer code may still use the values below, e.g. for `include`
+        let return_depth = self.return_stack.len();
+        let snapshot = match self.error_recovery {
+            ErrorRecovery::Restore => Some(self.data_stack.clone()),
+            _ => None,
+        };
+        while let Some(result) = self.eval_next() {
             result.or_else(|err| {
-                self.data_stack.clear();
+                // the host may want to inspect the stack after termination
+                if let Bye(_) = err {
+                    return Err(err);
+                }
+                self.recover(return_depth, snapshot.as_deref());
                 // those are just early exit statuses
                 if err == Quit || err == Exit || err == Leave {
                     return Ok(());
@@ -45,19 +304,138 @@ impl Forth {
         Ok(())
     }
 
+    /// Reset the stacks after an error, according to the error recovery policy.
+    fn recover(&mut self, return_depth: usize, snapshot: Option<&[Int]>) {
+        self.return_stack.truncate(return_depth);
+        match (self.error_recovery, snapshot) {
+            (ErrorRecovery::Clear, _) => self.data_stack.clear(),
+            (ErrorRecovery::Restore, Some(snapshot)) => {
+                self.data_stack.clear();
+                self.data_stack.extend_from_slice(snapshot);
+            }
+            _ => (),
+        }
+    }
+
     /// Evaluate a file.
     pub fn eval_file(&mut self, path: &str) -> Result<(), Error> {
         let script = fs::read_to_string(path).map_err(|msg| CustomError(msg.to_string()))?;
-        self.eval_string(&script)?;
+        self.eval_parser(Parser::from_file(&script, path))
+    }
+
+    /// Write the image of the interpreter holding the dictionary, including the compiled
+    /// functions, the memory, and the configuration. The stacks are not saved.
+    pub fn write_image(&self, mut writer: impl Write) -> Result<(), Error> {
+        writer
+            .write_all(&image::encode(self)?)
+            .map_err(|msg| CustomError(msg.to_string()))
+    }
+
+    /// Replace the dictionary, the memory, and the configuration with the ones read
+    /// from the image, see `write_image`. The native functions used in the image
+    /// need to be defined with the same names before loading it.
+    pub fn read_image(&mut self, mut reader: impl Read) -> Result<(), Error> {
+        // the running code refers to the slots that would be replaced
+        if self.running > 0 {
+            return Err(CustomError("cannot load an image while running".into()));
+        }
+        let mut bytes = Vec::new();
+        reader
+            .read_to_end(&mut bytes)
+            .map_err(|msg| CustomError(msg.to_string()))?;
+        let image = image::decode(self, &bytes)?;
+        self.dictionary = image.dictionary;
+        self.slots = image.slots;
+        self.buildins = image.buildins;
+        self.memory = image.memory;
+        self.heap = image.heap;
+        self.optimize = image.optimize;
+        self.max_call_depth = image.max_call_depth;
+        self.max_stack_depth = image.max_stack_depth;
+        self.max_return_stack_depth = image.max_return_stack_depth;
+        self.max_memory = image.max_memory;
+        self.error_recovery = image.error_recovery;
         Ok(())
     }
 
+    /// Save the image of the interpreter to the file, see `write_image`.
+    pub fn save_image(&self, path: &str) -> Result<(), Error> {
+        // encode before creating the file, so it is not truncated if saving fails
+        let image = image::encode(self)?;
+        fs::write(path, image).map_err(|msg| CustomError(msg.to_string()))
+    }
+
+    /// Load the image of the interpreter from the file, see `read_image`.
+    pub fn load_image(&mut self, path: &str) -> Result<(), Error> {
+        let file = fs::File::open(path).map_err(|msg| CustomError(msg.to_string()))?;
+        self.read_image(io::BufReader::new(file))
+    }
+
     /// Go to next word and evaluate it.
     #[inline]
-    pub(crate) fn eval_next(&mut self, parser: &mut Parser) -> Option<Result<(), Error>> {
-        match parser.next()? {
-            Ok(expr) => Some(expr.execute(self)),
-            Err(msg) => Some(Err(msg)),
+    pub(crate) fn eval_next(&mut self) -> Option<Result<(), Error>> {
+        let next = self.parser.next();
+        // `.(` prints while parsing
+        let output = self.parser.take_output();
+        if !output.is_empty() {
+            if let Err(err) = self.print(&output) {
+                return Some(Err(err));
+            }
+        }
+        match next? {
+            Ok(expr) => Some(
+                self.use_fuel()
+                    .and_then(|()| expr.execute(self))
+                    .and_then(|()| self.check_stacks())
+                    .map_err(|err| err.at(self.parser.location())),
+            ),
+            Err(msg) => Some(Err(msg.at(self.parser.location()))),
+        }
+    }
+
+    /// Use the fuel for executing a single instruction.
+    #[inline]
+    pub(crate) fn use_fuel(&mut self) -> Result<(), Error> {
+        match &mut self.fuel {
+            Some(0) => Err(OutOfFuel),
+            Some(fuel) => {
+                *fuel -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Check if the execution was interrupted, clear the flag if it was.
+    #[inline]
+    pub(crate) fn check_interrupt(&self) -> Result<(), Error> {
+        if self.interrupt.load(atomic::Ordering::Relaxed)
+            && self.interrupt.swap(false, atomic::Ordering::Relaxed)
+        {
+            return Err(Interrupted);
+        }
+        Ok(())
+    }
+
+    /// Check if the stacks did not exceed their maximal depths.
+    #[inline]
+    pub(crate) fn check_stacks(&self) -> Result<(), Error> {
+        if self.data_stack.len() > self.max_stack_depth {
+            return Err(StackOverflow);
+        }
+        if self.return_stack.len() > self.max_return_stack_depth {
+            return Err(ReturnStackOverflow);
+        }
+        Ok(())
+    }
+
+    /// Check if `count` more memory cells can be used without exceeding the limit.
+    #[inline]
+    pub(crate) fn check_memory(&self, count: usize) -> Result<(), Error> {
+        let used = self.memory.len() + self.heap.size();
+        match used.checked_add(count) {
+            Some(total) if total <= self.max_memory => Ok(()),
+            _ => Err(MemoryLimitExceeded),
         }
     }
 
@@ -75,12 +453,29 @@ impl Forth {
 
     /// Pop two values from the stack, return them in the order they were entered into the stack.
     #[inline]
-    pub(crate) fn stack_pop2(&mut self) -> Result<(Int, Int), Error> {
+    pub fn stack_pop2(&mut self) -> Result<(Int, Int), Error> {
         let b = self.stack_pop()?;
         let a = self.stack_pop()?;
         Ok((a, b))
     }
 
+    /// Push the values to the stack, the last one ends up on the top.
+    #[inline]
+    pub fn stack_extend(&mut self, values: impl IntoIterator<Item = Int>) {
+        self.data_stack.extend(values)
+    }
+
+    /// Pop `N` values from the stack, return them in the order they were entered into the stack.
+    /// Errors without changing the stack if there are not enough values.
+    #[inline]
+    pub fn stack_pop_n<const N: usize>(&mut self) -> Result<[Int; N], Error> {
+        let start = self.data_stack.len().checked_sub(N).ok_or(StackUnderflow)?;
+        let mut values = [0; N];
+        values.copy_from_slice(&self.data_stack[start..]);
+        self.data_stack.truncate(start);
+        Ok(values)
+    }
+
     /// Reference to the last value on the stack. Errors on empty stack.
     #[inline]
     pub fn stack_last(&mut self) -> Result<&Int, Error> {
@@ -89,7 +484,7 @@ impl Forth {
 
     /// Mutable reference to the last element on the stack. Errors on empty stack.
     #[inline]
-    pub(crate) fn stack_last_mut(&mut self) -> Result<&mut Int, Error> {
+    pub fn stack_last_mut(&mut self) -> Result<&mut Int, Error> {
         self.data_stack.last_mut().ok_or(StackUnderflow)
     }
 
@@ -99,25 +494,154 @@ impl Forth {
         self.data_stack.len()
     }
 
-    /// Define a new word, return an error on redefinition.
+    /// The values on the return stack, the last one is on the top.
     #[inline]
-    pub(crate) fn define_word(&mut self, name: &str, value: Expr) -> Result<(), Error> {
-        if self.dictionary.insert(name.into(), value).is_some() {
+    pub fn return_stack(&self) -> &[Int] {
+        &self.return_stack
+    }
+
+    /// Read the value from the memory address.
+    pub fn read_memory(&self, addr: Int) -> Result<Int, Error> {
+        let value = if addr == TO_IN {
+            Some(self.parser.position() as Int)
+        } else if addr >= WORD_BUFFER {
+            let index = (addr - WORD_BUFFER) as usize;
+            self.word_buffer.get(index).copied()
+        } else if addr >= ARGUMENTS {
+            let index = (addr - ARGUMENTS) as usize;
+            let mut chars = self.arguments.iter().flat_map(|arg| arg.chars());
+            chars.nth(index).map(|c| c as Int)
+        } else if addr >= INPUT_BUFFER {
+            let index = (addr - INPUT_BUFFER) as usize;
+            self.parser.source().get(index).map(|c| *c as Int)
+        } else if addr >= HEAP {
+            self.heap.get(addr).copied()
+        } else {
+            self.memory.get(addr as usize).copied()
+        };
+        value.ok_or(InvalidAddress)
+    }
+
+    /// Write the value to the memory address. Writing to the first free address allocates it.
+    /// The input buffer and the command-line arguments are read-only.
+    pub fn write_memory(&mut self, addr: Int, value: Int) -> Result<(), Error> {
+        if addr == TO_IN {
+            self.parser.set_position(value.max(0) as usize);
+        } else if addr >= WORD_BUFFER {
+            let index = (addr - WORD_BUFFER) as usize;
+            let cell = self.word_buffer.get_mut(index).ok_or(InvalidAddress)?;
+            *cell = value;
+        } else if addr >= INPUT_BUFFER {
+            return Err(InvalidAddress);
+        } else if addr >= HEAP {
+            let cell = self.heap.get_mut(addr).ok_or(InvalidAddress)?;
+            *cell = value;
+        } else {
+            let addr = addr as usize;
+            match addr.cmp(&self.memory.len()) {
+                Ordering::Greater => return Err(InvalidAddress),
+                Ordering::Equal => {
+                    self.check_memory(1)?;
+                    self.memory.push(value)
+                }
+                Ordering::Less => self.memory[addr] = value,
+            }
+        }
+        Ok(())
+    }
+
+    /// Define the word implemented by the Rust closure, which, unlike the builtins, can capture state.
+    /// Return an error on redefinition, the word is defined anyway.
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        func: impl FnMut(&mut Forth) -> Result<(), Error> + 'static,
+    ) -> Result<(), Error> {
+        self.define_word(name, Expr::Native(Native::new(func)))
+    }
+
+    /// Define a new word, return an error on redefinition. The redefined word gets a new slot,
+    /// so the code compiled earlier still uses the previous definition. The compiled functions
+    /// are checked the same way as when loading an image, so they cannot break the interpreter.
+    #[inline]
+    pub fn define_word(&mut self, name: &str, value: Expr) -> Result<(), Error> {
+        if !is_valid_expr(&value, self.slots.len()) {
+            return Err(CustomError(format!("invalid code of {}", name)));
+        }
+        let redefined = self.get_word(name).is_some();
+        let slot = self.reserve_word(name);
+        self.slots[slot].1 = Some(value);
+        if redefined {
             return Err(Redefined(name.into()));
         }
         Ok(())
     }
 
+    /// Return the slot for the word that is about to be defined. Reuse the slot of the word that
+    /// was used but not defined yet, otherwise create an empty slot.
+    #[inline]
+    pub(crate) fn reserve_word(&mut self, name: &str) -> usize {
+        if let Some(&slot) = self.dictionary.get(name) {
+            if self.slots[slot].1.is_none() {
+                return slot;
+            }
+        }
+        self.new_slot(name)
+    }
+
+    /// Return the slot of the word, create an empty slot if the word does not exist yet.
+    #[inline]
+    pub(crate) fn word_slot(&mut self, name: &str) -> usize {
+        match self.dictionary.get(name) {
+            Some(&slot) => slot,
+            None => self.new_slot(name),
+        }
+    }
+
+    /// Create an empty slot for the word.
+    #[inline]
+    fn new_slot(&mut self, name: &str) -> usize {
+        let slot = self.slots.len();
+        self.slots.push((name.into(), None));
+        self.dictionary.insert(name.into(), slot);
+        slot
+    }
+
     /// Get the compiled object associated to the word.
     #[inline]
-    pub(crate) fn get_word(&self, name: &str) -> Option<Expr> {
-        self.dictionary.get(name).cloned()
+    pub fn get_word(&self, name: &str) -> Option<Expr> {
+        let slot = *self.dictionary.get(name)?;
+        self.slots[slot].1.clone()
+    }
+
+    /// Execute the word, as if it was called from the Forth code.
+    pub fn call_word(&mut self, name: &str) -> Result<(), Error> {
+        let return_depth = self.return_stack.len();
+        let result = match self.dictionary.get(name) {
+            Some(&slot) => execute_slot(self, slot),
+            None => Err(UnknownWord(name.into())),
+        };
+        match result {
+            // those are just early exit statuses, see `eval_input`
+            Err(Quit | Exit | Leave) => {
+                self.return_stack.truncate(return_depth);
+                Ok(())
+            }
+            result => result,
+        }
     }
 
-    /// The list of all the words in the dictionary.
+    /// The list of all the words in the dictionary, sorted by name.
     #[inline]
-    pub(crate) fn words(&self) -> Vec<String> {
-        let mut words = self.dictionary.keys().map(|s| s.into()).collect::<Vec<_>>();
+    pub fn words(&self) -> Vec<String> {
+        let mut words = self
+            .dictionary
+            .values()
+            .filter_map(|&slot| match &self.slots[slot] {
+                (name, Some(_)) => Some(name.clone()),
+                (_, None) => None,
+            })
+            .collect::<Vec<_>>();
         words.sort();
         words
     }
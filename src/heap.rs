@@ -0,0 +1,92 @@
+use crate::{
+    forth::{HEAP, INPUT_BUFFER},
+    numbers::Int,
+};
+use std::collections::BTreeMap;
+
+/// The memory region for dynamically allocated blocks, separate from the dictionary space.
+/// The blocks are placed at the first gap in the address space that can hold them.
+#[derive(Clone, Debug, Default)]
+pub struct Heap(BTreeMap<Int, Vec<Int>>);
+
+impl FromIterator<(Int, Vec<Int>)> for Heap {
+    fn from_iter<T: IntoIterator<Item = (Int, Vec<Int>)>>(iter: T) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl Heap {
+    /// Allocate a block of `size` cells, return its address or `None` if there is no space left.
+    pub fn allocate(&mut self, size: usize) -> Option<Int> {
+        let addr = self.find_gap(size)?;
+        self.0.insert(addr, vec![0; size]);
+        Some(addr)
+    }
+
+    /// Free the block starting at `addr`, return `false` if there is no such block.
+    pub fn free(&mut self, addr: Int) -> bool {
+        self.0.remove(&addr).is_some()
+    }
+
+    /// Change the size of the block starting at `addr`, the block may be moved. Return its new address,
+    /// or `None` if there is no such block or no space left, in such a case the block is not changed.
+    pub fn resize(&mut self, addr: Int, size: usize) -> Option<Int> {
+        let mut block = self.0.remove(&addr)?;
+        match self.find_gap(size) {
+            Some(new) => {
+                block.resize(size, 0);
+                self.0.insert(new, block);
+                Some(new)
+            }
+            None => {
+                self.0.insert(addr, block);
+                None
+            }
+        }
+    }
+
+    /// Number of the cells in the block starting at `addr`.
+    pub fn block_size(&self, addr: Int) -> Option<usize> {
+        self.0.get(&addr).map(|block| block.len())
+    }
+
+    /// Total number of the allocated cells.
+    pub fn size(&self) -> usize {
+        self.0.values().map(|block| block.len()).sum()
+    }
+
+    /// The addresses and the content of the allocated blocks.
+    pub fn blocks(&self) -> impl Iterator<Item = (Int, &[Int])> {
+        self.0.iter().map(|(addr, block)| (*addr, block.as_slice()))
+    }
+
+    /// Reference to the cell at the address.
+    pub fn get(&self, addr: Int) -> Option<&Int> {
+        let (start, block) = self.0.range(..=addr).next_back()?;
+        block.get((addr - start) as usize)
+    }
+
+    /// Mutable reference to the cell at the address.
+    pub fn get_mut(&mut self, addr: Int) -> Option<&mut Int> {
+        let (start, block) = self.0.range_mut(..=addr).next_back()?;
+        block.get_mut((addr - start) as usize)
+    }
+
+    /// Find the first gap between the blocks that can hold `size` cells.
+    fn find_gap(&self, size: usize) -> Option<Int> {
+        // empty blocks still need unique addresses
+        let size = Int::try_from(size.max(1)).ok()?;
+        let mut start = HEAP;
+        for (addr, block) in &self.0 {
+            if addr - start >= size {
+                break;
+            }
+            start = addr + block.len().max(1) as Int;
+        }
+        if INPUT_BUFFER - start >= size {
+            Some(start)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,544 @@
+use crate::{
+    buildins::{buildin, buildin_name},
+    errors::Error::{self, InvalidImage},
+    expressions::Expr,
+    forth::{ErrorRecovery, Forth, HEAP, INPUT_BUFFER},
+    heap::Heap,
+    instructions::{is_valid_code, Instr},
+    numbers::Int,
+};
+use std::{collections::HashMap, rc::Rc};
+
+/// The bytes the image starts with.
+const MAGIC: &[u8; 4] = b"FRTH";
+/// The version of the image format, images of other versions cannot be loaded.
+const VERSION: u32 = 1;
+
+/// The part of the interpreter state saved in the image.
+pub(crate) struct Image {
+    pub dictionary: HashMap<String, usize>,
+    pub slots: Vec<(String, Option<Expr>)>,
+    pub buildins: usize,
+    pub memory: Vec<Int>,
+    pub heap: Heap,
+    pub optimize: bool,
+    pub max_call_depth: usize,
+    pub max_stack_depth: usize,
+    pub max_return_stack_depth: usize,
+    pub max_memory: usize,
+    pub error_recovery: ErrorRecovery,
+}
+
+/// Serialize the dictionary, the memory and the configuration of the interpreter.
+/// Fails if the dictionary contains functions that cannot be loaded back.
+pub(crate) fn encode(forth: &Forth) -> Result<Vec<u8>, Error> {
+    let mut encoder = Encoder(Vec::new());
+    encoder.0.extend_from_slice(MAGIC);
+    encoder.u32(VERSION);
+
+    encoder.bool(forth.optimize);
+    encoder.usize(forth.max_call_depth);
+    encoder.usize(forth.max_stack_depth);
+    encoder.usize(forth.max_return_stack_depth);
+    encoder.usize(forth.max_memory);
+    encoder.u8(forth.error_recovery as u8);
+
+    encoder.usize(forth.buildins);
+    encoder.usize(forth.slots.len());
+    for (name, definition) in &forth.slots {
+        encoder.str(name);
+        match definition {
+            Some(expr) => {
+                encoder.bool(true);
+                encoder.expr(expr)?;
+            }
+            None => encoder.bool(false),
+        }
+    }
+    let mut dictionary = forth.dictionary.iter().collect::<Vec<_>>();
+    dictionary.sort();
+    encoder.usize(dictionary.len());
+    for (name, slot) in dictionary {
+        encoder.str(name);
+        encoder.usize(*slot);
+    }
+
+    encoder.ints(&forth.memory);
+    let blocks = forth.heap.blocks().collect::<Vec<_>>();
+    encoder.usize(blocks.len());
+    for (addr, block) in blocks {
+        encoder.int(addr);
+        encoder.ints(block);
+    }
+    Ok(encoder.0)
+}
+
+/// Deserialize the image. The native functions are looked up by name in the dictionary
+/// of the interpreter, so they need to be defined before loading the image.
+pub(crate) fn decode(forth: &Forth, bytes: &[u8]) -> Result<Image, Error> {
+    let mut decoder = Decoder {
+        bytes,
+        pos: 0,
+        slots: 0,
+    };
+    if decoder.take(MAGIC.len())? != MAGIC {
+        return Err(InvalidImage("not an image".into()));
+    }
+    let version = decoder.u32()?;
+    if version != VERSION {
+        return Err(InvalidImage(format!("unsupported version {}", version)));
+    }
+
+    let optimize = decoder.bool()?;
+    let max_call_depth = decoder.usize()?;
+    let max_stack_depth = decoder.usize()?;
+    let max_return_stack_depth = decoder.usize()?;
+    let max_memory = decoder.usize()?;
+    let error_recovery = match decoder.u8()? {
+        0 => ErrorRecovery::Clear,
+        1 => ErrorRecovery::Preserve,
+        2 => ErrorRecovery::Restore,
+        other => return Err(InvalidImage(format!("invalid error recovery {}", other))),
+    };
+
+    let buildins = decoder.usize()?;
+    let count = decoder.usize()?;
+    decoder.slots = count;
+    let mut slots = Vec::new();
+    for _ in 0..count {
+        let name = decoder.string()?;
+        let definition = if decoder.bool()? {
+            Some(decoder.expr(forth, &name)?)
+        } else {
+            None
+        };
+        slots.push((name, definition));
+    }
+    let count = decoder.usize()?;
+    let mut dictionary = HashMap::new();
+    for _ in 0..count {
+        let name = decoder.string()?;
+        let slot = decoder.usize()?;
+        dictionary.insert(name, slot);
+    }
+    if dictionary.values().any(|slot| *slot >= slots.len()) {
+        return Err(InvalidImage("invalid slot".into()));
+    }
+
+    let memory = decoder.ints()?;
+    let count = decoder.usize()?;
+    let mut blocks = Vec::new();
+    for _ in 0..count {
+        let addr = decoder.int()?;
+        blocks.push((addr, decoder.ints()?));
+    }
+    if decoder.pos != bytes.len() {
+        return Err(InvalidImage("unexpected data at the end".into()));
+    }
+    blocks.sort_by_key(|(addr, _)| *addr);
+    if !is_valid_heap(&blocks) {
+        return Err(InvalidImage("invalid heap blocks".into()));
+    }
+
+    Ok(Image {
+        dictionary,
+        slots,
+        buildins,
+        memory,
+        heap: blocks.into_iter().collect(),
+        optimize,
+        max_call_depth,
+        max_stack_depth,
+        max_return_stack_depth,
+        max_memory,
+        error_recovery,
+    })
+}
+
+/// Check if the heap blocks sorted by their addresses are inside of the heap region
+/// and do not overlap, so allocating more blocks cannot break the interpreter.
+fn is_valid_heap(blocks: &[(Int, Vec<Int>)]) -> bool {
+    let mut start = HEAP;
+    blocks.iter().all(|(addr, block)| {
+        // the empty blocks still take an address, see `Heap::find_gap`
+        let end = Int::try_from(block.len().max(1))
+            .ok()
+            .and_then(|len| addr.checked_add(len));
+        match end {
+            Some(end) if *addr >= start && end <= INPUT_BUFFER => {
+                start = end;
+                true
+            }
+            _ => false,
+        }
+    })
+}
+
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn usize(&mut self, value: usize) {
+        self.0.extend_from_slice(&(value as u64).to_le_bytes());
+    }
+
+    fn int(&mut self, value: Int) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn ints(&mut self, values: &[Int]) {
+        self.usize(values.len());
+        for value in values {
+            self.int(*value);
+        }
+    }
+
+    fn str(&mut self, value: &str) {
+        self.usize(value.len());
+        self.0.extend_from_slice(value.as_bytes());
+    }
+
+    fn exprs(&mut self, exprs: &[Expr]) -> Result<(), Error> {
+        self.usize(exprs.len());
+        for expr in exprs {
+            self.expr(expr)?;
+        }
+        Ok(())
+    }
+
+    fn code(&mut self, code: &[Instr]) -> Result<(), Error> {
+        self.usize(code.len());
+        for instr in code {
+            self.instr(instr)?;
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Result<(), Error> {
+        use Expr::*;
+        match expr {
+            Word(word) => {
+                self.u8(0);
+                self.str(word);
+            }
+            Print(string) => {
+                self.u8(1);
+                self.str(string);
+            }
+            AbortQuote(msg) => {
+                self.u8(2);
+                self.str(msg);
+            }
+            Char(value) => {
+                self.u8(3);
+                self.int(*value);
+            }
+            Callable(func) => {
+                self.u8(4);
+                // the callables defined outside of the builtins table cannot be found when loading
+                let name = buildin_name(*func).ok_or_else(|| {
+                    InvalidImage("only the builtin functions can be saved".into())
+                })?;
+                self.str(name);
+            }
+            // the native function is looked up by the name of the word when loading
+            Native(_) => self.u8(5),
+            NewFunction(name, body) => {
+                self.u8(6);
+                self.str(name);
+                self.exprs(body)?;
+            }
+            Function(code, source) => {
+                self.u8(7);
+                self.code(code)?;
+                // without optimizations, the code is the same
+                if Rc::ptr_eq(code, source) {
+                    self.bool(false);
+                } else {
+                    self.bool(true);
+                    self.code(source)?;
+                }
+            }
+            IfElseThen(then, other) => {
+                self.u8(8);
+                self.exprs(then)?;
+                self.exprs(other)?;
+            }
+            Begin(body) => {
+                self.u8(9);
+                self.exprs(body)?;
+            }
+            Loop(body) => {
+                self.u8(10);
+                self.exprs(body)?;
+            }
+            NewConstant(name) => {
+                self.u8(11);
+                self.str(name);
+            }
+            Value(value) => {
+                self.u8(12);
+                self.int(*value);
+            }
+            NewVariable(name) => {
+                self.u8(13);
+                self.str(name);
+            }
+            NewCreate(name) => {
+                self.u8(14);
+                self.str(name);
+            }
+            NewValue(name) => {
+                self.u8(15);
+                self.str(name);
+            }
+            ToValue(name) => {
+                self.u8(16);
+                self.str(name);
+            }
+            Include(path) => {
+                self.u8(17);
+                self.str(path);
+            }
+            See(word) => {
+                self.u8(18);
+                self.str(word);
+            }
+            Dummy => self.u8(19),
+            SaveImage(path) => {
+                self.u8(20);
+                self.str(path);
+            }
+            LoadImage(path) => {
+                self.u8(21);
+                self.str(path);
+            }
+        }
+        Ok(())
+    }
+
+    fn instr(&mut self, instr: &Instr) -> Result<(), Error> {
+        use Instr::*;
+        let (tag, arg) = match instr {
+            Push(value) => (0, Some(*value as i64)),
+            Call(slot) => (1, Some(*slot as i64)),
+            TailCall(slot) => (2, Some(*slot as i64)),
+            If(target) => (3, Some(*target as i64)),
+            Else(target) => (4, Some(*target as i64)),
+            Begin(target) => (5, Some(*target as i64)),
+            While => (6, None),
+            Until(target) => (7, Some(*target as i64)),
+            Again(target) => (8, Some(*target as i64)),
+            Repeat(target) => (9, Some(*target as i64)),
+            Do(target) => (10, Some(*target as i64)),
+            Loop(target) => (11, Some(*target as i64)),
+            Leave => (12, None),
+            Exit => (13, None),
+            Expr(expr) => {
+                self.u8(14);
+                self.expr(expr)?;
+                return Ok(());
+            }
+            AddConst(value) => (15, Some(*value as i64)),
+            Nip => (16, None),
+            TwoDup => (17, None),
+            TwoDrop => (18, None),
+        };
+        self.u8(tag);
+        if let Some(arg) = arg {
+            self.0.extend_from_slice(&arg.to_le_bytes());
+        }
+        Ok(())
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// Number of the dictionary slots the compiled code can call.
+    slots: usize,
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], Error> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len());
+        let end = end.ok_or_else(|| InvalidImage("unexpected end of the image".into()))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, Error> {
+        Ok(self.u8()? != 0)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn usize(&mut self) -> Result<usize, Error> {
+        // the limits saved on 64-bit platforms can be larger than `usize`
+        let value = u64::from_le_bytes(self.array()?);
+        Ok(usize::try_from(value).unwrap_or(usize::MAX))
+    }
+
+    fn i64(&mut self) -> Result<i64, Error> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    fn int(&mut self) -> Result<Int, Error> {
+        Ok(Int::from_le_bytes(self.array()?))
+    }
+
+    /// Read the number of items, checking that the image is long enough to hold them.
+    fn len(&mut self, item_size: usize) -> Result<usize, Error> {
+        let len = self.usize()?;
+        if len.saturating_mul(item_size) > self.bytes.len() - self.pos {
+            return Err(InvalidImage("unexpected end of the image".into()));
+        }
+        Ok(len)
+    }
+
+    fn ints(&mut self) -> Result<Vec<Int>, Error> {
+        let len = self.len(size_of::<Int>())?;
+        (0..len).map(|_| self.int()).collect()
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let len = self.len(1)?;
+        let bytes = self.take(len)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| InvalidImage("invalid string".into()))
+    }
+
+    fn exprs(&mut self, forth: &Forth, name: &str) -> Result<Vec<Expr>, Error> {
+        let len = self.len(1)?;
+        (0..len).map(|_| self.expr(forth, name)).collect()
+    }
+
+    fn code(&mut self, forth: &Forth, name: &str) -> Result<Rc<[Instr]>, Error> {
+        let len = self.len(1)?;
+        let code = (0..len)
+            .map(|_| self.instr(forth, name))
+            .collect::<Result<Rc<[Instr]>, Error>>()?;
+        if !is_valid_code(&code, self.slots) {
+            return Err(InvalidImage(format!("invalid code of {}", name)));
+        }
+        Ok(code)
+    }
+
+    /// Read the expression, `name` is the word it defines.
+    fn expr(&mut self, forth: &Forth, name: &str) -> Result<Expr, Error> {
+        use Expr::*;
+        let expr = match self.u8()? {
+            0 => Word(self.string()?),
+            1 => Print(self.string()?),
+            2 => AbortQuote(self.string()?),
+            3 => Char(self.int()?),
+            4 => {
+                let func = self.string()?;
+                match buildin(&func) {
+                    Some(expr @ Callable(_)) => expr.clone(),
+                    _ => return Err(InvalidImage(format!("unknown builtin {}", func))),
+                }
+            }
+            5 => match forth.get_word(name) {
+                Some(expr @ Native(_)) => expr,
+                _ => {
+                    return Err(InvalidImage(format!(
+                        "native function {} needs to be defined",
+                        name
+                    )))
+                }
+            },
+            6 => {
+                let name = self.string()?;
+                let body = self.exprs(forth, &name)?;
+                NewFunction(name, body)
+            }
+            7 => {
+                let code = self.code(forth, name)?;
+                let source = if self.bool()? {
+                    self.code(forth, name)?
+                } else {
+                    code.clone()
+                };
+                Function(code, source)
+            }
+            8 => IfElseThen(self.exprs(forth, name)?, self.exprs(forth, name)?),
+            9 => Begin(self.exprs(forth, name)?),
+            10 => Loop(self.exprs(forth, name)?),
+            11 => NewConstant(self.string()?),
+            12 => Value(self.int()?),
+            13 => NewVariable(self.string()?),
+            14 => NewCreate(self.string()?),
+            15 => NewValue(self.string()?),
+            16 => ToValue(self.string()?),
+            17 => Include(self.string()?),
+            18 => See(self.string()?),
+            19 => Dummy,
+            20 => SaveImage(self.string()?),
+            21 => LoadImage(self.string()?),
+            other => return Err(InvalidImage(format!("invalid expression {}", other))),
+        };
+        Ok(expr)
+    }
+
+    fn instr(&mut self, forth: &Forth, name: &str) -> Result<Instr, Error> {
+        use Instr::*;
+        let instr = match self.u8()? {
+            0 => Push(self.value()?),
+            1 => Call(self.index()?),
+            2 => TailCall(self.index()?),
+            3 => If(self.index()?),
+            4 => Else(self.index()?),
+            5 => Begin(self.index()?),
+            6 => While,
+            7 => Until(self.index()?),
+            8 => Again(self.index()?),
+            9 => Repeat(self.index()?),
+            10 => Do(self.index()?),
+            11 => Loop(self.index()?),
+            12 => Leave,
+            13 => Exit,
+            14 => Expr(self.expr(forth, name)?),
+            15 => AddConst(self.value()?),
+            16 => Nip,
+            17 => TwoDup,
+            18 => TwoDrop,
+            other => return Err(InvalidImage(format!("invalid instruction {}", other))),
+        };
+        Ok(instr)
+    }
+
+    fn value(&mut self) -> Result<Int, Error> {
+        Int::try_from(self.i64()?).map_err(|_| InvalidImage("invalid value".into()))
+    }
+
+    fn index(&mut self) -> Result<usize, Error> {
+        usize::try_from(self.i64()?).map_err(|_| InvalidImage("invalid index".into()))
+    }
+}
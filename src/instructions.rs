@@ -0,0 +1,454 @@
+use crate::{
+    errors::Error::{self, Exit, Leave, ReturnStackOverflow},
+    expressions::{execute_slot, Expr},
+    forth::Forth,
+    numbers::{is_true, Int},
+};
+use std::rc::Rc;
+
+/// The instruction of the compiled code. The jump targets are the indexes of the instructions
+/// in the code.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Instr {
+    /// Push the value to the stack.
+    Push(Int),
+    /// Execute the word in the dictionary slot.
+    Call(usize),
+    /// Execute the word in the dictionary slot as the last thing in the function, so the called
+    /// function can replace the caller rather than returning to it.
+    TailCall(usize),
+    /// `if`: jump to the target if the flag is false.
+    If(usize),
+    /// `else`: jump to the target.
+    Else(usize),
+    /// `begin`: start the loop ending at the target.
+    Begin(usize),
+    /// `while`: leave the loop if the flag is false.
+    While,
+    /// `until`: leave the loop if the flag is true, otherwise jump to the target.
+    Until(usize),
+    /// `again`: jump to the target.
+    Again(usize),
+    /// `repeat`: jump to the target.
+    Repeat(usize),
+    /// `do`: start the counted loop ending at the target.
+    Do(usize),
+    /// `loop`: increment the loop index and jump to the target if the limit was not reached.
+    Loop(usize),
+    /// `leave`: leave the loop.
+    Leave,
+    /// `exit`: return from the function.
+    Exit,
+    /// Execute the expression.
+    Expr(Expr),
+    /// Add the constant to the value on top of the stack, the fused `n +`.
+    AddConst(Int),
+    /// `nip`, the fused `swap drop`.
+    Nip,
+    /// `2dup`, the fused `over over`.
+    TwoDup,
+    /// `2drop`, the fused `drop drop`.
+    TwoDrop,
+}
+
+/// The loop that is currently executed.
+struct LoopFrame {
+    /// Where the execution continues after leaving the loop.
+    exit: usize,
+    /// The index and the limit of the counted loop.
+    counter: Option<(Int, Int)>,
+}
+
+/// What happens after executing the instruction.
+enum Next {
+    /// Continue with the next instruction.
+    Continue,
+    /// Call the function in the dictionary slot.
+    Call(usize, Rc<[Instr]>),
+    /// Call the function in the dictionary slot that replaces the current one.
+    TailCall(usize, Rc<[Instr]>),
+}
+
+/// The function that is currently executed.
+pub(crate) struct Frame {
+    /// The dictionary slot of the function, `None` for the code that is not a named function.
+    slot: Option<usize>,
+    /// The compiled code of the function.
+    code: Rc<[Instr]>,
+    /// Index of the next instruction.
+    pc: usize,
+    /// The loops that are currently executed.
+    loops: Vec<LoopFrame>,
+}
+
+impl Frame {
+    fn new(slot: Option<usize>, code: Rc<[Instr]>) -> Self {
+        Self {
+            slot,
+            code,
+            pc: 0,
+            loops: Vec::new(),
+        }
+    }
+}
+
+/// Execute the compiled code of the function in the dictionary slot, or the unnamed code
+/// if the slot is `None`. The functions called by the code are executed in the same loop,
+/// their callers wait in `Forth::frames`, so the execution does not use the Rust stack.
+pub fn run(forth: &mut Forth, slot: Option<usize>, code: Rc<[Instr]>) -> Result<(), Error> {
+    forth.running += 1;
+    let result = run_frames(forth, slot, code);
+    forth.running -= 1;
+    result
+}
+
+/// Execute the code, see `run`.
+fn run_frames(forth: &mut Forth, slot: Option<usize>, code: Rc<[Instr]>) -> Result<(), Error> {
+    // the frames below belong to other `run` calls, e.g. from `include`
+    let base = forth.frames.len();
+    let mut frame = Frame::new(slot, code);
+    loop {
+        let result = match frame.code.get(frame.pc) {
+            Some(instr) => {
+                frame.pc += 1;
+                forth
+                    .use_fuel()
+                    .and_then(|()| step(forth, instr, &mut frame.pc, &mut frame.loops))
+                    .and_then(|next| forth.check_stacks().map(|()| next))
+            }
+            None => {
+                // end of the function, return to the caller
+                match caller(forth, base) {
+                    Some(caller) => frame = caller,
+                    None => return Ok(()),
+                }
+                continue;
+            }
+        };
+        match result {
+            Ok(Next::Continue) => (),
+            Ok(Next::TailCall(slot, callee)) => frame = Frame::new(Some(slot), callee),
+            Ok(Next::Call(slot, callee)) => {
+                if forth.frames.len() + 2 > forth.max_call_depth {
+                    let err = ReturnStackOverflow.called_from(call_chain(forth, base, &frame));
+                    unwind(forth, base, frame);
+                    return Err(err);
+                }
+                let caller = std::mem::replace(&mut frame, Frame::new(Some(slot), callee));
+                forth.frames.push(caller);
+            }
+            Err(Leave) => {
+                // `leave` called by another word leaves the innermost loop,
+                // it can be in one of the callers
+                loop {
+                    if let Some(inner) = frame.loops.pop() {
+                        frame.pc = leave(forth, inner);
+                        break;
+                    }
+                    match caller(forth, base) {
+                        Some(caller) => frame = caller,
+                        None => return Err(Leave),
+                    }
+                }
+            }
+            Err(Exit) => {
+                drop_loops(forth, &mut frame);
+                match caller(forth, base) {
+                    Some(caller) => frame = caller,
+                    None => return Err(Exit),
+                }
+            }
+            Err(err) => {
+                let err = err.called_from(call_chain(forth, base, &frame));
+                unwind(forth, base, frame);
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Execute a single instruction.
+#[inline]
+fn step(
+    forth: &mut Forth,
+    instr: &Instr,
+    pc: &mut usize,
+    loops: &mut Vec<LoopFrame>,
+) -> Result<Next, Error> {
+    use Instr::*;
+    match instr {
+        Push(value) => forth.stack_push(*value),
+        Call(slot) | TailCall(slot) => {
+            forth.check_interrupt()?;
+            if let (_, Some(crate::expressions::Expr::Function(code, _))) = &forth.slots[*slot] {
+                let code = code.clone();
+                if let TailCall(_) = instr {
+                    return Ok(Next::TailCall(*slot, code));
+                }
+                return Ok(Next::Call(*slot, code));
+            }
+            execute_slot(forth, *slot)?
+        }
+        If(target) => {
+            if !is_true(forth.stack_pop()?) {
+                *pc = *target;
+            }
+        }
+        Else(target) => *pc = *target,
+        Again(target) | Repeat(target) => {
+            forth.check_interrupt()?;
+            *pc = *target;
+        }
+        Begin(exit) => loops.push(LoopFrame {
+            exit: *exit,
+            counter: None,
+        }),
+        While => {
+            if !is_true(forth.stack_pop()?) {
+                return Err(Error::Leave);
+            }
+        }
+        Until(target) => {
+            if is_true(forth.stack_pop()?) {
+                loops.pop();
+            } else {
+                forth.check_interrupt()?;
+                *pc = *target;
+            }
+        }
+        Do(exit) => {
+            let (limit, index) = forth.stack_pop2()?;
+            if index < limit {
+                forth.return_stack.push(index);
+                loops.push(LoopFrame {
+                    exit: *exit,
+                    counter: Some((index, limit)),
+                });
+            } else {
+                *pc = *exit;
+            }
+        }
+        Loop(target) => {
+            let frame = loops.last_mut().expect("loop without do");
+            let (index, limit) = frame.counter.as_mut().expect("loop without do");
+            *index += 1;
+            forth.return_stack.pop();
+            if *index < *limit {
+                forth.return_stack.push(*index);
+                forth.check_interrupt()?;
+                *pc = *target;
+            } else {
+                loops.pop();
+            }
+        }
+        Leave => return Err(Error::Leave),
+        Exit => return Err(Error::Exit),
+        Expr(expr) => expr.execute(forth)?,
+        AddConst(value) => {
+            *forth.stack_last_mut()? += value;
+        }
+        Nip => {
+            let (_, b) = forth.stack_pop2()?;
+            forth.stack_push(b);
+        }
+        TwoDup => {
+            let (a, b) = forth.stack_pop2()?;
+            forth.data_stack.extend([a, b, a, b]);
+        }
+        TwoDrop => {
+            forth.stack_pop2()?;
+        }
+    }
+    Ok(Next::Continue)
+}
+
+/// Leave the loop, return the address where the execution continues.
+#[inline]
+fn leave(forth: &mut Forth, frame: LoopFrame) -> usize {
+    let exit = frame.exit;
+    drop_loop(forth, frame);
+    exit
+}
+
+/// Remove the index of the counted loop from the return stack.
+#[inline]
+fn drop_loop(forth: &mut Forth, frame: LoopFrame) {
+    if frame.counter.is_some() {
+        forth.return_stack.pop();
+    }
+}
+
+/// Remove all the loops of the function.
+#[inline]
+fn drop_loops(forth: &mut Forth, frame: &mut Frame) {
+    while let Some(inner) = frame.loops.pop() {
+        drop_loop(forth, inner);
+    }
+}
+
+/// Pop the caller of the current function, if it belongs to the `run` call.
+#[inline]
+fn caller(forth: &mut Forth, base: usize) -> Option<Frame> {
+    if forth.frames.len() > base {
+        forth.frames.pop()
+    } else {
+        None
+    }
+}
+
+/// The names of the word executed by the current instruction, the current function and its callers
+/// belonging to the `run` call, starting with the innermost one.
+fn call_chain<'a>(
+    forth: &'a Forth,
+    base: usize,
+    frame: &'a Frame,
+) -> impl Iterator<Item = String> + 'a {
+    let word = match frame.pc.checked_sub(1).map(|pc| &frame.code[pc]) {
+        Some(Instr::Call(slot) | Instr::TailCall(slot)) if forth.slots[*slot].1.is_some() => {
+            Some(*slot)
+        }
+        _ => None,
+    };
+    let functions = std::iter::once(frame)
+        .chain(forth.frames[base..].iter().rev())
+        .filter_map(|frame| frame.slot);
+    word.into_iter()
+        .chain(functions)
+        .map(|slot| forth.slots[slot].0.clone())
+}
+
+/// Remove the frame and all its callers belonging to the `run` call.
+fn unwind(forth: &mut Forth, base: usize, mut frame: Frame) {
+    drop_loops(forth, &mut frame);
+    while let Some(mut frame) = caller(forth, base) {
+        drop_loops(forth, &mut frame);
+    }
+}
+
+/// Check if the compiled code is structured the way the compiler generates it: the called
+/// slots exist, the loops are properly nested, and the jumps do not go outside of the code
+/// or the loops, so executing it cannot break the interpreter.
+pub(crate) fn is_valid_code(code: &[Instr], slots: usize) -> bool {
+    use Instr::*;
+    // the innermost loop enclosing each instruction, and the end of the code
+    let mut scopes = Vec::with_capacity(code.len() + 1);
+    let mut open: Vec<usize> = Vec::new();
+    for (i, instr) in code.iter().enumerate() {
+        scopes.push(open.last().copied());
+        match instr {
+            Call(slot) | TailCall(slot) if *slot >= slots => return false,
+            // the tail call would drop the loops of the replaced function
+            TailCall(_) if !open.is_empty() => return false,
+            Begin(_) | Do(_) => open.push(i),
+            Expr(expr) if !is_valid_expr(expr, slots) => return false,
+            While if !matches!(open.last().map(|start| &code[*start]), Some(Begin(_))) => {
+                return false
+            }
+            Until(target) | Again(target) | Repeat(target) | Loop(target) => {
+                let Some(start) = open.pop() else {
+                    return false;
+                };
+                let exit = match (&code[start], instr) {
+                    (Begin(exit), Until(_) | Again(_) | Repeat(_)) | (Do(exit), Loop(_)) => *exit,
+                    _ => return false,
+                };
+                if *target != start + 1 || exit != i + 1 {
+                    return false;
+                }
+            }
+            _ => (),
+        }
+    }
+    if !open.is_empty() {
+        return false;
+    }
+    scopes.push(None);
+
+    // the conditional blocks jump forward, within the same loop
+    code.iter().enumerate().all(|(i, instr)| match instr {
+        If(target) | Else(target) => *target > i && scopes.get(*target) == Some(&scopes[i]),
+        _ => true,
+    })
+}
+
+/// Check if the compiled functions in the expression are valid, see `is_valid_code`.
+pub(crate) fn is_valid_expr(expr: &Expr, slots: usize) -> bool {
+    let valid = |body: &[Expr]| body.iter().all(|expr| is_valid_expr(expr, slots));
+    match expr {
+        Expr::Function(code, source) => is_valid_code(code, slots) && is_valid_code(source, slots),
+        Expr::IfElseThen(then, other) => valid(then) && valid(other),
+        Expr::Begin(body) | Expr::Loop(body) | Expr::NewFunction(_, body) => valid(body),
+        _ => true,
+    }
+}
+
+/// Transform the compiled code back to the source code.
+pub fn decompile(forth: &Forth, code: &[Instr]) -> String {
+    use Instr::*;
+
+    // the `then` words are not compiled, they are the jump targets
+    let mut thens = vec![0; code.len() + 1];
+    for instr in code {
+        match instr {
+            // for if-else-then, `if` jumps right after `else`
+            If(target) if *target > 0 && matches!(code[target - 1], Else(_)) => (),
+            If(target) | Else(target) => thens[*target] += 1,
+            _ => (),
+        }
+    }
+
+    let mut words = Vec::new();
+    for (i, instr) in code.iter().enumerate() {
+        words.extend(std::iter::repeat_n("then".to_string(), thens[i]));
+        words.push(instr.to_source(forth));
+    }
+    words.extend(std::iter::repeat_n("then".to_string(), thens[code.len()]));
+    words.join(" ")
+}
+
+impl Instr {
+    /// Transform the instruction back to the source code.
+    fn to_source(&self, forth: &Forth) -> String {
+        use Instr::*;
+        match self {
+            Push(value) => value.to_string(),
+            Call(slot) | TailCall(slot) => forth.slots[*slot].0.clone(),
+            If(_) => "if".into(),
+            Else(_) => "else".into(),
+            Begin(_) => "begin".into(),
+            While => "while".into(),
+            Until(_) => "until".into(),
+            Again(_) => "again".into(),
+            Repeat(_) => "repeat".into(),
+            Do(_) => "do".into(),
+            Loop(_) => "loop".into(),
+            Leave => "leave".into(),
+            Exit => "exit".into(),
+            Expr(expr) => expr.to_string(),
+            AddConst(value) => format!("{} +", value),
+            Nip => "nip".into(),
+            TwoDup => "2dup".into(),
+            TwoDrop => "2drop".into(),
+        }
+    }
+
+    /// The jump target of the instruction.
+    pub(crate) fn target(&self) -> Option<usize> {
+        use Instr::*;
+        match self {
+            If(target) | Else(target) | Begin(target) | Until(target) | Again(target)
+            | Repeat(target) | Do(target) | Loop(target) => Some(*target),
+            _ => None,
+        }
+    }
+
+    /// Mutable reference to the jump target of the instruction, to relocate the code.
+    pub(crate) fn target_mut(&mut self) -> Option<&mut usize> {
+        use Instr::*;
+        match self {
+            If(target) | Else(target) | Begin(target) | Until(target) | Again(target)
+            | Repeat(target) | Do(target) | Loop(target) => Some(target),
+            _ => None,
+        }
+    }
+}
@@ -1,13 +1,26 @@
 mod buildins;
+mod clock;
+mod compiler;
 mod errors;
 mod expressions;
 mod forth;
+mod heap;
+mod image;
+mod instructions;
 mod numbers;
+mod optimizer;
 mod parser;
+mod random;
 mod reader;
 
+pub use crate::clock::{Clock, SystemClock, VirtualClock};
 pub use crate::errors::Error;
-pub use crate::forth::Forth;
+pub use crate::expressions::{Expr, Native};
+pub use crate::forth::{ErrorRecovery, Forth};
+pub use crate::instructions::Instr;
+pub use crate::numbers::Int;
+pub use crate::parser::Parser;
+pub use crate::reader::Location;
 
 #[cfg(test)]
 mod tests;
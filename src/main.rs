@@ -1,5 +1,8 @@
-use forth_rs::Forth;
-use std::env;
+use forth_rs::{Error::Bye, ErrorRecovery, Forth};
+use std::{
+    env,
+    io::{self, Read, Write},
+};
 
 macro_rules! die {
     ( $msg:expr ) => {{
@@ -9,54 +12,150 @@ macro_rules! die {
 }
 
 #[cfg(feature = "repl")]
-fn repl(forth: &mut Forth) {
-    use rustyline::{error::ReadlineError, DefaultEditor};
-
-    println!("Press ^C to exit.\n");
-
-    let mut rl = DefaultEditor::new().unwrap();
-    loop {
-        match rl.readline("> ") {
-            Ok(line) => {
-                let _ = rl.add_history_entry(line.as_str());
-                match forth.eval_string(&line) {
-                    Ok(_) => println!(" ok"),
-                    Err(msg) => println!(" error: {}", msg),
-                }
-            }
-            Err(ReadlineError::Interrupted | ReadlineError::Eof) => {
-                break;
-            }
-            Err(err) => die!(err),
-        }
-    }
+mod repl;
+
+/// Flush the output and exit with the code.
+fn exit(code: i32) -> ! {
+    let _ = io::stdout().flush();
+    std::process::exit(code);
 }
 
-fn print_help(args: Vec<String>) {
-    println!("Usage: {} [FILE]...", args[0]);
+fn print_help(program: &str) {
+    println!(
+        "Usage: {} [OPTION]... [-e CODE]... [FILE | -] [ARG]...",
+        program
+    );
+    println!("\nRuns the code given with -e, then the script read from FILE (or stdin for -).");
+    println!("The script can read its name and ARGs with the argc and arg words.");
+    println!("\n  -e, --eval     evaluate the code, can be used multiple times");
+    println!("  --image        start from the image saved with save-image");
+    println!("  --no-prelude   do not load the standard library (include/std.f)");
+    println!("  --no-optimize  do not optimize the compiled functions");
+    println!("  --on-error     what happens with the stack on errors: clear it (default),");
+    println!("                 preserve it, or restore it as it was before the line");
+    #[cfg(feature = "repl")]
+    println!("  --atomic       in REPL, undo all the changes made by the line that failed");
     #[cfg(feature = "repl")]
-    println!("\n\nIf no files are given, opens REPL.");
+    println!("\nIf neither code nor script is given, opens REPL.");
+}
+
+/// Take the value of the option.
+fn value(args: &mut impl Iterator<Item = String>, option: &str) -> String {
+    match args.next() {
+        Some(value) => value,
+        None => die!(format!("{} needs a value", option)),
+    }
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args = env::args();
+    let program = args.next().unwrap_or_else(|| "forthrs".into());
+
+    let mut image = None;
+    let mut prelude = true;
+    let mut optimize = true;
+    let mut error_recovery = None;
+    #[cfg(feature = "repl")]
+    let mut atomic = false;
+    let mut code = Vec::new();
+    let mut script = None;
+
+    // the options end with the first argument that is not an option, the script,
+    // the arguments following it are passed to the script
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print_help(&program);
+                return;
+            }
+            "-e" | "--eval" => code.push(value(&mut args, &arg)),
+            "--image" => image = Some(value(&mut args, &arg)),
+            "--no-prelude" => prelude = false,
+            "--no-optimize" => optimize = false,
+            "--on-error" => {
+                let policy = match value(&mut args, &arg).as_str() {
+                    "clear" => ErrorRecovery::Clear,
+                    "preserve" => ErrorRecovery::Preserve,
+                    "restore" => ErrorRecovery::Restore,
+                    _ => die!("--on-error needs to be one of: clear, preserve, restore"),
+                };
+                error_recovery = Some(policy);
+            }
+            #[cfg(feature = "repl")]
+            "--atomic" => atomic = true,
+            "--" => {
+                script = args.next();
+                break;
+            }
+            option if option.starts_with('-') && option != "-" => {
+                die!(format!("unknown option {}", option))
+            }
+            _ => {
+                script = Some(arg);
+                break;
+            }
+        }
+    }
 
     let mut forth = Forth::new(1024);
+    let configure = |forth: &mut Forth| {
+        if !optimize {
+            forth.set_optimize(false);
+        }
+        if let Some(policy) = error_recovery {
+            forth.set_error_recovery(policy);
+        }
+    };
+    // the prelude is compiled with the configuration given by the options
+    configure(&mut forth);
+    if let Some(path) = image {
+        if let Err(err) = forth.load_image(&path) {
+            die!(err);
+        }
+        // the options override the configuration saved in the image
+        configure(&mut forth);
+    } else if prelude {
+        if let Err(err) = forth.load_prelude() {
+            die!(err);
+        }
+    }
+
+    let name = script.clone().unwrap_or_else(|| program.clone());
+    forth.set_arguments(std::iter::once(name).chain(args));
 
     #[cfg(feature = "repl")]
-    if args.len() < 2 {
-        repl(&mut forth);
-        return;
+    if code.is_empty() && script.is_none() {
+        let code = repl::repl(&mut forth, atomic);
+        exit(code);
     }
 
-    if args.len() < 2 || &args[1] == "-h" || &args[1] == "--help" {
-        print_help(args);
+    if code.is_empty() && script.is_none() {
+        print_help(&program);
         return;
     }
 
-    for path in &args[1..] {
-        if let Err(err) = forth.eval_file(path) {
-            die!(err);
+    for code in &code {
+        match forth.eval_string(code) {
+            Ok(()) => (),
+            Err(Bye(code)) => exit(code),
+            Err(err) => die!(err),
+        }
+    }
+
+    let result = match script.as_deref() {
+        Some("-") => {
+            let mut code = String::new();
+            if let Err(err) = io::stdin().read_to_string(&mut code) {
+                die!(err);
+            }
+            forth.eval_string(&code)
         }
+        Some(path) => forth.eval_file(path),
+        None => Ok(()),
+    };
+    match result {
+        Ok(()) => exit(0),
+        Err(Bye(code)) => exit(code),
+        Err(err) => die!(err),
     }
 }
@@ -0,0 +1,198 @@
+use crate::{
+    expressions::Expr,
+    forth::Forth,
+    instructions::Instr::{self, *},
+    numbers::{from_bool, Int},
+};
+use std::collections::HashSet;
+
+/// The maximal number of instructions of the function that is inlined.
+const INLINE_LIMIT: usize = 8;
+
+/// Optimize the compiled code: inline small functions, fold the constant expressions,
+/// and replace common sequences of words with fused instructions.
+pub fn optimize(forth: &Forth, code: &[Instr]) -> Vec<Instr> {
+    let code = code
+        .iter()
+        .map(|instr| match instr {
+            TailCall(slot) => Call(*slot),
+            other => other.clone(),
+        })
+        .collect::<Vec<_>>();
+    let mut code = inline(forth, &code);
+    while let Some(optimized) = peephole(forth, &code) {
+        code = optimized;
+    }
+    code
+}
+
+/// Replace the calls to small, non-recursive functions with their code.
+fn inline(forth: &Forth, code: &[Instr]) -> Vec<Instr> {
+    let mut optimized = Vec::new();
+    // new indexes of the instructions, to update the jump targets
+    let mut moved = Vec::new();
+    // the instructions that were not inlined, so their targets need to be updated
+    let mut original = Vec::new();
+    for instr in code {
+        moved.push(optimized.len());
+        match inlined(forth, instr) {
+            Some(body) => {
+                let offset = optimized.len();
+                for instr in body.iter() {
+                    let mut instr = match instr {
+                        TailCall(slot) => Call(*slot),
+                        other => other.clone(),
+                    };
+                    if let Some(target) = instr.target_mut() {
+                        *target += offset;
+                    }
+                    optimized.push(instr);
+                }
+            }
+            None => {
+                original.push(optimized.len());
+                optimized.push(instr.clone());
+            }
+        }
+    }
+    moved.push(optimized.len());
+    for i in original {
+        if let Some(target) = optimized[i].target_mut() {
+            *target = moved[*target];
+        }
+    }
+    optimized
+}
+
+/// The code of the function called by the instruction, if it can be inlined.
+fn inlined<'a>(forth: &'a Forth, instr: &Instr) -> Option<&'a [Instr]> {
+    let Call(slot) = instr else {
+        return None;
+    };
+    match &forth.slots[*slot].1 {
+        Some(Expr::Function(code, _))
+            if code.len() <= INLINE_LIMIT
+                && !code.iter().any(|instr| match instr {
+                    // `exit` would return from the caller
+                    Exit => true,
+                    Call(other) | TailCall(other) => other == slot,
+                    _ => false,
+                }) =>
+        {
+            Some(code)
+        }
+        _ => None,
+    }
+}
+
+/// Apply the rewrite rules to the code, return `None` if nothing changed.
+fn peephole(forth: &Forth, code: &[Instr]) -> Option<Vec<Instr>> {
+    // the sequences containing jump targets cannot be rewritten
+    let targets = code
+        .iter()
+        .filter_map(|instr| instr.target())
+        .collect::<HashSet<_>>();
+
+    let mut changed = false;
+    let mut optimized = Vec::new();
+    let mut moved = vec![0; code.len() + 1];
+    let mut i = 0;
+    while i < code.len() {
+        moved[i] = optimized.len();
+        let rewritten = (2..=3).rev().find_map(|len| {
+            let window = code.get(i..i + len)?;
+            if (i + 1..i + len).any(|j| targets.contains(&j)) {
+                return None;
+            }
+            rewrite(forth, window)
+        });
+        match rewritten {
+            Some((len, replacement)) => {
+                moved[i + 1..i + len].fill(optimized.len());
+                optimized.extend(replacement);
+                changed = true;
+                i += len;
+            }
+            None => {
+                optimized.push(code[i].clone());
+                i += 1;
+            }
+        }
+    }
+    moved[code.len()] = optimized.len();
+
+    if !changed {
+        return None;
+    }
+    for instr in optimized.iter_mut() {
+        if let Some(target) = instr.target_mut() {
+            *target = moved[*target];
+        }
+    }
+    Some(optimized)
+}
+
+/// Rewrite the sequence of instructions, return the number of the replaced instructions
+/// and the replacement.
+fn rewrite(forth: &Forth, window: &[Instr]) -> Option<(usize, Vec<Instr>)> {
+    let buildin = |instr: &Instr| match instr {
+        Call(slot) if *slot < forth.buildins => Some(forth.slots[*slot].0.as_str()),
+        _ => None,
+    };
+    let replacement = match window {
+        [Push(a), Push(b), op] => vec![Push(fold_binary(buildin(op)?, *a, *b)?)],
+        [Push(a), op] => {
+            let name = buildin(op)?;
+            match (name, fold_unary(name, *a)) {
+                (_, Some(value)) => vec![Push(value)],
+                ("+", None) => vec![AddConst(*a)],
+                ("-", None) => vec![AddConst(a.checked_neg()?)],
+                _ => return None,
+            }
+        }
+        [AddConst(a), AddConst(b)] => vec![AddConst(a.checked_add(*b)?)],
+        [first, second] => match (buildin(first)?, buildin(second)?) {
+            ("swap", "drop") => vec![Nip],
+            ("over", "over") => vec![TwoDup],
+            ("drop", "drop") => vec![TwoDrop],
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some((window.len(), replacement))
+}
+
+/// Calculate the result of the operation on two constants, `None` if it cannot be folded
+/// because it fails or overflows, so the error happens at runtime.
+fn fold_binary(op: &str, a: Int, b: Int) -> Option<Int> {
+    match op {
+        "+" => a.checked_add(b),
+        "-" => a.checked_sub(b),
+        "*" => a.checked_mul(b),
+        "/" => a.checked_div(b),
+        "mod" => a.checked_rem(b),
+        "and" => Some(a & b),
+        "or" => Some(a | b),
+        "xor" => Some(a ^ b),
+        "=" => Some(from_bool(a == b)),
+        "<>" => Some(from_bool(a != b)),
+        "<" => Some(from_bool(a < b)),
+        ">" => Some(from_bool(a > b)),
+        _ => None,
+    }
+}
+
+/// Calculate the result of the operation on a constant, `None` if it cannot be folded.
+fn fold_unary(op: &str, a: Int) -> Option<Int> {
+    match op {
+        "negate" => a.checked_neg(),
+        "abs" => a.checked_abs(),
+        "1+" => a.checked_add(1),
+        "1-" => a.checked_sub(1),
+        "2*" => Some(a << 1),
+        "2/" => Some(a >> 1),
+        "0=" => Some(from_bool(a == 0)),
+        "invert" => Some(!a),
+        _ => None,
+    }
+}
@@ -1,29 +1,39 @@
 use crate::{
-    errors::Error::{self, MissingArgument, ParsingError},
+    errors::Error::{self, IncompleteInput, MissingArgument, ParsingError},
     expressions::Expr::{self, *},
     numbers::Int,
-    reader::Reader,
+    reader::{Location, Reader},
 };
 
 /// The parser that can read the code.
-pub struct Parser<'a>(Reader<'a>);
+#[derive(Debug, Default)]
+pub struct Parser {
+    reader: Reader,
+    /// The text printed by `.(` while parsing, kept until the interpreter takes it
+    /// with `take_output`.
+    output: String,
+    /// The path of the parsed file.
+    file: Option<String>,
+    /// Line and column where the last expression read at the top level starts.
+    start: (usize, usize),
+}
 
-impl<'a> Parser<'a> {
+impl Parser {
     /// Skip whitespaces until any non-whitespace character. Do not pop the character.
     #[inline]
     fn skip_whitespaces(&mut self) {
-        while let Some(c) = self.0.peek() {
+        while let Some(c) = self.reader.peek() {
             if !c.is_whitespace() {
                 break;
             }
-            self.0.next();
+            self.reader.next();
         }
     }
 
     /// Read all the characters until the `delimiter` (exclusive).
     #[inline]
     pub fn read_until(&mut self, delimiter: char) -> Result<String, Error> {
-        let reader = &mut self.0;
+        let reader = &mut self.reader;
         let mut string = String::new();
         for c in reader {
             if c == delimiter {
@@ -31,13 +41,13 @@ impl<'a> Parser<'a> {
             }
             string.push(c);
         }
-        Err(ParsingError(format!("missing '{}'", delimiter)))
+        Err(IncompleteInput(format!("missing '{}'", delimiter)))
     }
 
     /// Read all the characters until a whitespace (exclusive).
     #[inline]
     fn read_word(&mut self) -> String {
-        let reader = &mut self.0;
+        let reader = &mut self.reader;
         reader
             .take_while(|c| !c.is_whitespace())
             .flat_map(|c| c.to_lowercase())
@@ -67,7 +77,7 @@ impl<'a> Parser<'a> {
             body.push(expr);
         }
 
-        Err(ParsingError("missing ';'".into()))
+        Err(IncompleteInput("missing ';'".into()))
     }
 
     /// Read the `if ... [else ...] then` block
@@ -97,7 +107,7 @@ impl<'a> Parser<'a> {
             acc.push(expr)
         }
 
-        Err(ParsingError("missing 'then'".into()))
+        Err(IncompleteInput("missing 'then'".into()))
     }
 
     /// Read the `begin ... again | until | repeat` block.
@@ -124,7 +134,7 @@ impl<'a> Parser<'a> {
             }
             body.push(expr)
         }
-        Err(ParsingError(
+        Err(IncompleteInput(
             "begin blocks needs to end with 'repeat', 'again', or 'until'".into(),
         ))
     }
@@ -145,7 +155,7 @@ impl<'a> Parser<'a> {
             body.push(expr)
         }
 
-        Err(ParsingError("do blocks needs to end with 'loop'".into()))
+        Err(IncompleteInput("do blocks needs to end with 'loop'".into()))
     }
 }
 
@@ -161,38 +171,129 @@ macro_rules! single_arg {
     }};
 }
 
-impl<'a> Iterator for Parser<'a> {
-    type Item = Result<Expr, Error>;
+impl Parser {
+    /// Create the parser for the code read from the file.
+    pub fn from_file(code: &str, path: &str) -> Self {
+        Self {
+            file: Some(path.into()),
+            ..Self::from(code)
+        }
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        // skip leading spaces
-        self.skip_whitespaces();
+    /// The location where the last expression starts.
+    pub fn location(&self) -> Location {
+        let (line, column) = self.start;
+        Location {
+            file: self.file.clone(),
+            line,
+            column,
+        }
+    }
 
-        let word = self.read_word();
-        match word.as_str() {
-            // end of input
-            "" => None,
-            // skip comments
-            "(" => {
-                for c in &mut self.0 {
-                    if c == ')' {
-                        return self.next();
-                    }
-                }
-                Some(Err(ParsingError("missing ')'".into())))
+    /// The current line of the input.
+    #[inline]
+    pub fn source(&self) -> &[char] {
+        self.reader.source()
+    }
+
+    /// Position in the current line of the input.
+    #[inline]
+    pub fn position(&self) -> usize {
+        self.reader.position()
+    }
+
+    /// Move to the position in the current line of the input.
+    #[inline]
+    pub fn set_position(&mut self, pos: usize) {
+        self.reader.set_position(pos)
+    }
+
+    /// Check if the code ends in the middle of an expression, like a definition
+    /// missing `;`, so more input is needed to parse it.
+    pub fn is_incomplete(code: &str) -> bool {
+        for expr in Parser::from(code) {
+            match expr {
+                Err(IncompleteInput(_)) => return true,
+                Err(_) => return false,
+                Ok(_) => (),
             }
-            "\\" => {
-                let reader = &mut self.0;
-                reader.take_while(|c| c != &'\n').for_each(drop);
-                self.next()
+        }
+        false
+    }
+
+    /// Take the text printed by `.(` while parsing.
+    #[inline]
+    pub(crate) fn take_output(&mut self) -> String {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Move to the next line of the input, return `false` if there are no more lines.
+    #[inline]
+    pub fn refill(&mut self) -> bool {
+        self.reader.refill()
+    }
+
+    /// Parse the characters from the current line until the `delimiter`, return the position
+    /// where the parsed string starts and its length. The delimiter is consumed.
+    #[inline]
+    pub fn parse(&mut self, delimiter: char) -> (usize, usize) {
+        self.parse_with(false, |c| c == delimiter)
+    }
+
+    /// Skip the leading whitespaces and parse the name delimited by a whitespace from
+    /// the current line, return the position where the name starts and its length.
+    #[inline]
+    pub fn parse_name(&mut self) -> (usize, usize) {
+        self.parse_with(true, char::is_whitespace)
+    }
+
+    /// Skip the leading `delimiter`s and parse the characters until the `delimiter` from
+    /// the current line, return the position where the parsed string starts and its length.
+    /// When the delimiter is a space, all the whitespaces are treated as delimiters.
+    #[inline]
+    pub fn word(&mut self, delimiter: char) -> (usize, usize) {
+        if delimiter == ' ' {
+            self.parse_name()
+        } else {
+            self.parse_with(true, |c| c == delimiter)
+        }
+    }
+
+    /// Parse the current line until the character matching `is_delimiter`.
+    fn parse_with<F>(&mut self, skip_leading: bool, is_delimiter: F) -> (usize, usize)
+    where
+        F: Fn(char) -> bool,
+    {
+        let source = self.reader.source();
+        let mut start = self.reader.position().min(source.len());
+        if skip_leading {
+            while start < source.len() && is_delimiter(source[start]) {
+                start += 1;
             }
+        }
+        let mut end = start;
+        while end < source.len() && !is_delimiter(source[end]) {
+            end += 1;
+        }
+        let len = source.len();
+        // skip the delimiter
+        self.reader.set_position(len.min(end + 1));
+        (start, end - start)
+    }
+}
+
+impl Parser {
+    /// Read the expression starting with the word.
+    #[inline]
+    fn read_expr(&mut self, word: &str) -> Option<Result<Expr, Error>> {
+        match word {
             // strings
             "char" => {
-                let result = match self.0.next() {
+                let result = match self.reader.next() {
                     None => Err(ParsingError("failed to read character".into())),
                     Some(c) => {
                         // ignore the rest of the word if there is any, this is how Forth behaves
-                        for c in &mut self.0 {
+                        for c in &mut self.reader {
                             if c.is_whitespace() {
                                 break;
                             }
@@ -202,17 +303,14 @@ impl<'a> Iterator for Parser<'a> {
                 };
                 Some(result)
             }
-            ".(" => match self.read_until(')') {
-                Ok(string) => {
-                    print!("{}", string);
-                    self.next()
-                }
-                Err(msg) => Some(Err(msg)),
-            },
             ".\"" => match self.read_until('"') {
                 Ok(string) => Some(Ok(Print(string))),
                 Err(msg) => Some(Err(msg)),
             },
+            "abort\"" => match self.read_until('"') {
+                Ok(string) => Some(Ok(AbortQuote(string))),
+                Err(msg) => Some(Err(msg)),
+            },
             // special forms
             ":" => Some(self.read_function()),
             "if" => Some(self.read_iet()),
@@ -240,15 +338,74 @@ impl<'a> Iterator for Parser<'a> {
             "see" => {
                 single_arg!(self, See)
             }
+            "save-image" => {
+                single_arg!(self, SaveImage)
+            }
+            "load-image" => {
+                single_arg!(self, LoadImage)
+            }
             // regular words
             word => Some(Ok(Word(word.into()))),
         }
     }
 }
 
-impl<'a> From<&'a str> for Parser<'a> {
-    fn from(value: &'a str) -> Self {
-        let reader = Reader::from(value);
-        Self(reader)
+impl Iterator for Parser {
+    type Item = Result<Expr, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // skip leading spaces
+        self.skip_whitespaces();
+        let start = self.reader.location();
+        self.start = start;
+
+        let word = self.read_word();
+        match word.as_str() {
+            // end of input
+            "" => None,
+            // skip comments
+            "(" => {
+                for c in &mut self.reader {
+                    if c == ')' {
+                        return self.next();
+                    }
+                }
+                Some(Err(IncompleteInput("missing ')'".into())))
+            }
+            "\\" => {
+                let reader = &mut self.reader;
+                reader.take_while(|c| c != &'\n').for_each(drop);
+                self.next()
+            }
+            // the shebang line of an executable script
+            word if word.starts_with("#!") && start == (1, 1) => {
+                let reader = &mut self.reader;
+                reader.take_while(|c| c != &'\n').for_each(drop);
+                self.next()
+            }
+            // instant print
+            ".(" => match self.read_until(')') {
+                Ok(string) => {
+                    self.output.push_str(&string);
+                    self.next()
+                }
+                Err(msg) => Some(Err(msg)),
+            },
+            word => {
+                let result = self.read_expr(word);
+                // the nested expressions were read in the meantime
+                self.start = start;
+                result
+            }
+        }
+    }
+}
+
+impl From<&str> for Parser {
+    fn from(value: &str) -> Self {
+        Self {
+            reader: Reader::from(value),
+            ..Default::default()
+        }
     }
 }
@@ -1,28 +1,116 @@
-use std::{iter::Peekable, str::Chars};
-
-/// The reader that traverses the string returning the characters. It has `pop` and `peek` functionalities.
-pub struct Reader<'a>(Peekable<Chars<'a>>);
+/// The reader that traverses the input line by line returning the characters. It has `pop` and `peek`
+/// functionalities and gives access to the current line and the position in it (`source` and `>in`).
+#[derive(Debug, Default)]
+pub struct Reader {
+    /// The lines of the input, including the trailing newline characters.
+    lines: Vec<Vec<char>>,
+    /// Index of the current line.
+    line: usize,
+    /// Position in the current line.
+    pos: usize,
+}
 
-impl<'a> Reader<'a> {
+impl Reader {
     /// Peek at the next character, but do not advance the iterator.
     #[inline]
     pub fn peek(&mut self) -> Option<&char> {
-        self.0.peek()
+        self.next_line_if_needed();
+        self.lines.get(self.line)?.get(self.pos)
+    }
+
+    /// Move to the next line if the current one was fully consumed.
+    #[inline]
+    fn next_line_if_needed(&mut self) {
+        while self.line + 1 < self.lines.len() && self.pos >= self.lines[self.line].len() {
+            self.line += 1;
+            self.pos = 0;
+        }
+    }
+
+    /// The current line, without the trailing newline.
+    #[inline]
+    pub fn source(&self) -> &[char] {
+        match self.lines.get(self.line) {
+            Some(line) => match line.last() {
+                Some('\n') => &line[..line.len() - 1],
+                _ => line,
+            },
+            None => &[],
+        }
+    }
+
+    /// The line and the column of the next character, both counted from one.
+    #[inline]
+    pub fn location(&mut self) -> (usize, usize) {
+        self.next_line_if_needed();
+        (self.line + 1, self.pos + 1)
+    }
+
+    /// Position in the current line.
+    #[inline]
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Move to the position in the current line.
+    #[inline]
+    pub fn set_position(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    /// Move to the beginning of the next line, return `false` if there are no more lines.
+    #[inline]
+    pub fn refill(&mut self) -> bool {
+        if self.line + 1 < self.lines.len() {
+            self.line += 1;
+            self.pos = 0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The location in the source code.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Location {
+    /// The path of the file, `None` for the code not read from a file.
+    pub file: Option<String>,
+    /// The line, counted from one.
+    pub line: usize,
+    /// The column, counted from one.
+    pub column: usize,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let file = self.file.as_deref().unwrap_or("<input>");
+        write!(f, "{}:{}:{}", file, self.line, self.column)
     }
 }
 
-impl<'a> Iterator for Reader<'a> {
+impl Iterator for Reader {
     type Item = char;
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
-        self.0.next()
+        let c = *self.peek()?;
+        self.pos += 1;
+        Some(c)
     }
 }
 
-impl<'a> From<&'a str> for Reader<'a> {
+impl From<&str> for Reader {
     /// Create the `Reader` from a string.
-    fn from(value: &'a str) -> Self {
-        Self(value.chars().peekable())
+    fn from(value: &str) -> Self {
+        let lines = value
+            .split_inclusive('\n')
+            .map(|line| line.chars().collect())
+            .collect();
+        Self {
+            lines,
+            line: 0,
+            pos: 0,
+        }
     }
 }
@@ -0,0 +1,454 @@
+use forth_rs::{Error::Bye, Forth, Int, Parser};
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Context, Editor, Helper,
+};
+use std::{
+    borrow::Cow, collections::BTreeSet, env, path::PathBuf, str::FromStr, sync::atomic::Ordering,
+};
+
+const KNOWN_WORD: &str = "\x1b[36m";
+const UNKNOWN_WORD: &str = "\x1b[31m";
+const NUMBER: &str = "\x1b[33m";
+const STRING: &str = "\x1b[32m";
+const COMMENT: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// Run the interactive session, return the exit code.
+pub fn repl(forth: &mut Forth, atomic: bool) -> i32 {
+    println!("Press ^C to exit, type #help for the REPL commands.\n");
+
+    // while the code is running, ^C interrupts it rather than killing the process,
+    // at the prompt it is handled by rustyline
+    let interrupt = forth.interrupt_handle();
+    if let Err(err) = signal_hook::flag::register(signal_hook::consts::SIGINT, interrupt.clone()) {
+        die!(err);
+    }
+
+    let mut rl = Editor::<ForthHelper, DefaultHistory>::new().unwrap();
+    rl.set_helper(Some(ForthHelper::new(forth)));
+    let history = history_path();
+    if let Some(path) = &history {
+        // there is no history on the first run
+        let _ = rl.load_history(path);
+    }
+
+    let mut settings = Settings::default();
+    // the lines of the unfinished input, like a definition spanning multiple lines
+    let mut input = String::new();
+    let code = loop {
+        let prompt = if input.is_empty() { "> " } else { "... " };
+        match rl.readline(prompt) {
+            Ok(line) => {
+                let _ = rl.add_history_entry(line.as_str());
+                if input.is_empty() && is_meta_command(&line) {
+                    match settings.update(&line) {
+                        Ok(()) => println!(" ok"),
+                        Err(msg) => println!(" error: {}", msg),
+                    }
+                    continue;
+                }
+                input.push_str(&line);
+                input.push('\n');
+                if Parser::is_incomplete(&input) {
+                    continue;
+                }
+                let code = std::mem::take(&mut input);
+                interrupt.store(false, Ordering::Relaxed);
+                let result = if atomic {
+                    forth.eval_string_atomic(&code)
+                } else {
+                    forth.eval_string(&code)
+                };
+                // the code could have defined new words
+                if let Some(helper) = rl.helper_mut() {
+                    *helper = ForthHelper::new(forth);
+                }
+                match result {
+                    Ok(_) => println!(" ok{}", settings.status(forth)),
+                    Err(Bye(code)) => break code,
+                    Err(msg) => println!(" error: {}", msg),
+                }
+            }
+            // ^C discards the unfinished input
+            Err(ReadlineError::Interrupted) if !input.is_empty() => input.clear(),
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => break 0,
+            Err(err) => die!(err),
+        }
+    };
+
+    if let Some(path) = &history {
+        if let Err(err) = rl.save_history(path) {
+            eprintln!("failed to save the history: {}", err);
+        }
+    }
+    code
+}
+
+/// The help for the REPL commands.
+const HELP: &str = "\
+#stack [off|depth|top|full]  show nothing, the depth, the top items, or all the items of
+                             the data stack after each line, without argument toggle it
+#rstack [on|off]             show the return stack after each line
+#help                        show this message";
+
+/// The number of the items shown by `#stack top`.
+const TOP_ITEMS: usize = 5;
+
+/// How the data stack is shown after each line.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum StackDisplay {
+    #[default]
+    Off,
+    /// The number of the items, like ` ok 3` in Gforth.
+    Depth,
+    /// The number of the items and a few items from the top.
+    Top,
+    /// All the items, like `.s`.
+    Full,
+}
+
+/// The settings changed by the REPL commands.
+#[derive(Default)]
+struct Settings {
+    stack: StackDisplay,
+    return_stack: bool,
+}
+
+/// The line is the REPL command rather than Forth code. Only the known commands are matched,
+/// so the words starting with `#` (like `#s` or the shebang) are passed to Forth.
+fn is_meta_command(line: &str) -> bool {
+    matches!(
+        line.split_whitespace().next(),
+        Some("#stack" | "#rstack" | "#help")
+    )
+}
+
+impl Settings {
+    /// Run the REPL command.
+    fn update(&mut self, line: &str) -> Result<(), String> {
+        let args = line.split_whitespace().collect::<Vec<_>>();
+        match args.as_slice() {
+            ["#stack"] if self.stack == StackDisplay::Off => self.stack = StackDisplay::Depth,
+            ["#stack"] => self.stack = StackDisplay::Off,
+            ["#stack", "off"] => self.stack = StackDisplay::Off,
+            ["#stack", "depth"] => self.stack = StackDisplay::Depth,
+            ["#stack", "top"] => self.stack = StackDisplay::Top,
+            ["#stack", "full"] => self.stack = StackDisplay::Full,
+            ["#rstack"] => self.return_stack = !self.return_stack,
+            ["#rstack", "on"] => self.return_stack = true,
+            ["#rstack", "off"] => self.return_stack = false,
+            ["#help"] => println!("{}", HELP),
+            _ => return Err(format!("invalid command, the commands are:\n{}", HELP)),
+        }
+        Ok(())
+    }
+
+    /// The stacks shown after ` ok`.
+    fn status(&self, forth: &Forth) -> String {
+        let stack = &forth.data_stack;
+        let mut status = match self.stack {
+            StackDisplay::Off => String::new(),
+            StackDisplay::Depth => format!(" {}", stack.len()),
+            StackDisplay::Top if stack.len() > TOP_ITEMS => {
+                let top = &stack[stack.len() - TOP_ITEMS..];
+                format!(" <{}> ... {}", stack.len(), join(top))
+            }
+            StackDisplay::Top | StackDisplay::Full => format!(" <{}> {}", stack.len(), join(stack)),
+        };
+        if self.return_stack {
+            let stack = forth.return_stack();
+            status += &format!(" | r: <{}> {}", stack.len(), join(stack));
+        }
+        status.trim_end().to_string()
+    }
+}
+
+/// The values separated with spaces.
+fn join(values: &[Int]) -> String {
+    values
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The file where the history is kept between the sessions.
+fn history_path() -> Option<PathBuf> {
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(".forthrs_history"))
+}
+
+/// Completes and highlights the words defined in the dictionary.
+struct ForthHelper {
+    words: BTreeSet<String>,
+}
+
+impl ForthHelper {
+    fn new(forth: &Forth) -> Self {
+        Self {
+            words: forth.words().into_iter().collect(),
+        }
+    }
+
+    /// The color of the word, depending on whether it is defined.
+    fn color(&self, word: &str) -> &'static str {
+        if self.words.contains(&word.to_lowercase()) {
+            KNOWN_WORD
+        } else if Int::from_str(word).is_ok() {
+            NUMBER
+        } else {
+            UNKNOWN_WORD
+        }
+    }
+}
+
+impl Completer for ForthHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(char::is_whitespace)
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let prefix = line[start..pos].to_lowercase();
+        let candidates = self
+            .words
+            .range(prefix.clone()..)
+            .take_while(|word| word.starts_with(&prefix))
+            .cloned()
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Highlighter for ForthHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        if is_meta_command(line) {
+            return Cow::Owned(format!("{}{}{}", KNOWN_WORD, line, RESET));
+        }
+        let mut highlighted = String::new();
+        let mut rest = line;
+        // the argument of the previous word, e.g. the name following `:`
+        let mut argument = false;
+        while !rest.is_empty() {
+            let start = rest
+                .find(|c: char| !c.is_whitespace())
+                .unwrap_or(rest.len());
+            highlighted.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let (word, tail) = rest.split_at(end);
+            if word.is_empty() {
+                break;
+            }
+            rest = tail;
+
+            if argument {
+                highlighted.push_str(word);
+                argument = false;
+                continue;
+            }
+            // the text following the word that is not a word itself
+            let (color, delimiter) = match word.to_lowercase().as_str() {
+                "\\" => (COMMENT, '\n'),
+                "(" => (COMMENT, ')'),
+                ".\"" | "abort\"" => (STRING, '"'),
+                ".(" => (STRING, ')'),
+                ":" | "variable" | "create" | "constant" | "value" | "to" | "char" | "see"
+                | "include" | "save-image" | "load-image" => {
+                    argument = true;
+                    highlighted.push_str(&format!("{}{}{}", KNOWN_WORD, word, RESET));
+                    continue;
+                }
+                _ => {
+                    highlighted.push_str(&format!("{}{}{}", self.color(word), word, RESET));
+                    continue;
+                }
+            };
+            let end = rest.find(delimiter).map(|i| i + 1).unwrap_or(rest.len());
+            let (text, tail) = rest.split_at(end);
+            let word_color = if color == COMMENT {
+                COMMENT
+            } else {
+                KNOWN_WORD
+            };
+            highlighted.push_str(&format!("{}{}{}{}{}", word_color, word, color, text, RESET));
+            rest = tail;
+        }
+        Cow::Owned(highlighted)
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        true
+    }
+}
+
+impl Hinter for ForthHelper {
+    type Hint = String;
+}
+
+impl Validator for ForthHelper {}
+
+impl Helper for ForthHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn helper() -> ForthHelper {
+        let mut forth = Forth::new(1024);
+        forth.eval_string(": square dup * ;").unwrap();
+        ForthHelper::new(&forth)
+    }
+
+    fn colored(color: &str, text: &str) -> String {
+        format!("{}{}{}", color, text, RESET)
+    }
+
+    #[test_case("sw", 2, 0, &["swap"]; "word prefix")]
+    #[test_case("1 2 sw", 6, 4, &["swap"]; "prefix after numbers")]
+    #[test_case("sq 3", 2, 0, &["square"]; "prefix in the middle of line")]
+    #[test_case("1 DR", 4, 2, &["drop"]; "uppercase prefix")]
+    #[test_case("1 d", 3, 2, &["depth", "do", "drop", "dump", "dup"]; "multiple candidates")]
+    #[test_case("1 xyz", 5, 2, &[]; "no candidates")]
+    fn completion(line: &str, pos: usize, start: usize, expected: &[&str]) {
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+        let (actual_start, candidates) = helper().complete(line, pos, &ctx).unwrap();
+        assert_eq!(actual_start, start);
+        assert_eq!(candidates, expected);
+    }
+
+    #[test]
+    fn highlight_words() {
+        let expected = format!(
+            "{} {} {}",
+            colored(NUMBER, "2"),
+            colored(KNOWN_WORD, "SQUARE"),
+            colored(UNKNOWN_WORD, "foo")
+        );
+        assert_eq!(helper().highlight("2 SQUARE foo", 0), expected);
+    }
+
+    #[test]
+    fn highlight_definition_name() {
+        let expected = format!(
+            "{} cube {} {} {} {}",
+            colored(KNOWN_WORD, ":"),
+            colored(KNOWN_WORD, "dup"),
+            colored(KNOWN_WORD, "square"),
+            colored(KNOWN_WORD, "*"),
+            colored(KNOWN_WORD, ";")
+        );
+        assert_eq!(helper().highlight(": cube dup square * ;", 0), expected);
+    }
+
+    #[test_case(".\" hello world\" cr", KNOWN_WORD, ".\"", STRING, " hello world\""; "print string")]
+    #[test_case(".( hello) cr", KNOWN_WORD, ".(", STRING, " hello)"; "print immediately")]
+    #[test_case("( n -- n ) cr", COMMENT, "(", COMMENT, " n -- n )"; "paren comment")]
+    fn highlight_text(line: &str, word_color: &str, word: &str, color: &str, text: &str) {
+        let expected = format!(
+            "{}{}{}{}{} {}",
+            word_color,
+            word,
+            color,
+            text,
+            RESET,
+            colored(KNOWN_WORD, "cr")
+        );
+        assert_eq!(helper().highlight(line, 0), expected);
+    }
+
+    #[test]
+    fn highlight_line_comment() {
+        let expected = format!(
+            "{} {}\\{} rest of ( line{}",
+            colored(KNOWN_WORD, "cr"),
+            COMMENT,
+            COMMENT,
+            RESET
+        );
+        assert_eq!(helper().highlight("cr \\ rest of ( line", 0), expected);
+    }
+
+    #[test]
+    fn highlight_unterminated_string() {
+        let expected = format!("{}{}{} hello{}", KNOWN_WORD, ".\"", STRING, RESET);
+        assert_eq!(helper().highlight(".\" hello", 0), expected);
+    }
+
+    #[test_case("#stack"; "stack")]
+    #[test_case("  #stack full"; "stack with argument")]
+    #[test_case("#rstack on"; "return stack")]
+    #[test_case("#help"; "help")]
+    fn meta_command(line: &str) {
+        assert!(is_meta_command(line));
+    }
+
+    #[test_case("#items"; "unknown command")]
+    #[test_case("#"; "hash")]
+    #[test_case("#!/usr/bin/env forthrs"; "shebang")]
+    #[test_case("1 #stack"; "command after code")]
+    fn not_meta_command(line: &str) {
+        assert!(!is_meta_command(line));
+    }
+
+    #[test_case(&["#stack"], StackDisplay::Depth, false; "toggle stack on")]
+    #[test_case(&["#stack", "#stack"], StackDisplay::Off, false; "toggle stack off")]
+    #[test_case(&["#stack full", "#stack"], StackDisplay::Off, false; "toggle full stack off")]
+    #[test_case(&["#stack depth"], StackDisplay::Depth, false; "stack depth")]
+    #[test_case(&["#stack top"], StackDisplay::Top, false; "stack top")]
+    #[test_case(&["#stack full"], StackDisplay::Full, false; "stack full")]
+    #[test_case(&["#stack full", "#stack off"], StackDisplay::Off, false; "stack off")]
+    #[test_case(&["#rstack"], StackDisplay::Off, true; "toggle return stack on")]
+    #[test_case(&["#rstack", "#rstack"], StackDisplay::Off, false; "toggle return stack off")]
+    #[test_case(&["#rstack on"], StackDisplay::Off, true; "return stack on")]
+    #[test_case(&["#rstack on", "#rstack off"], StackDisplay::Off, false; "return stack off")]
+    #[test_case(&["#help"], StackDisplay::Off, false; "help")]
+    fn settings_commands(commands: &[&str], stack: StackDisplay, return_stack: bool) {
+        let mut settings = Settings::default();
+        for command in commands {
+            settings.update(command).unwrap();
+        }
+        assert_eq!(settings.stack, stack);
+        assert_eq!(settings.return_stack, return_stack);
+    }
+
+    #[test_case("#stack all"; "invalid stack argument")]
+    #[test_case("#rstack full"; "invalid return stack argument")]
+    #[test_case("#stack top full"; "too many arguments")]
+    #[test_case("#help me"; "help with argument")]
+    fn invalid_settings_command(command: &str) {
+        let mut settings = Settings::default();
+        let err = settings.update(command).unwrap_err();
+        assert!(err.starts_with("invalid command"));
+        assert_eq!(settings.stack, StackDisplay::Off);
+        assert!(!settings.return_stack);
+    }
+
+    #[test_case(StackDisplay::Off, false, "1 2 3", ""; "nothing")]
+    #[test_case(StackDisplay::Depth, false, "1 2 3", " 3"; "depth")]
+    #[test_case(StackDisplay::Depth, false, "", " 0"; "depth of empty stack")]
+    #[test_case(StackDisplay::Top, false, "1 2 3", " <3> 1 2 3"; "top of short stack")]
+    #[test_case(StackDisplay::Top, false, "1 2 3 4 5 6 7", " <7> ... 3 4 5 6 7"; "top of long stack")]
+    #[test_case(StackDisplay::Full, false, "1 2 3 4 5 6 7", " <7> 1 2 3 4 5 6 7"; "full stack")]
+    #[test_case(StackDisplay::Full, false, "", " <0>"; "full empty stack")]
+    #[test_case(StackDisplay::Off, true, "1 2", " | r: <0>"; "empty return stack")]
+    #[test_case(StackDisplay::Depth, true, "1 2 >r", " 1 | r: <1> 2"; "depth and return stack")]
+    fn settings_status(stack: StackDisplay, return_stack: bool, code: &str, expected: &str) {
+        let mut forth = Forth::new(1024);
+        forth.eval_string(code).unwrap();
+        let settings = Settings {
+            stack,
+            return_stack,
+        };
+        assert_eq!(settings.status(&forth), expected);
+    }
+}
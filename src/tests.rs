@@ -1,19 +1,31 @@
 use crate::{
-    errors::Error::{self, DivisionByZero, StackUnderflow},
-    expressions::Expr::{self, Begin, Char, IfElseThen, Loop, NewFunction, Word},
-    forth::Forth,
-    numbers::{Int, FALSE, TRUE},
+    clock::VirtualClock,
+    compiler::compile,
+    errors::Error::{self, DivisionByZero, InvalidAddress, StackUnderflow},
+    expressions::Expr::{self, AbortQuote, Begin, Char, IfElseThen, Loop, NewFunction, Word},
+    forth::{ErrorRecovery, Forth, HEAP, INPUT_BUFFER},
+    instructions::{decompile, Instr},
+    numbers::{from_bool, Int, FALSE, TRUE},
     parser::Parser,
 };
+use std::{rc::Rc, time::Duration};
 use test_case::test_case;
 
+/// The path of a temporary file that is unique for the test run, so the tests do not collide.
+fn temp_path(name: &str) -> std::path::PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("forth-rs-{}-{}-{}", std::process::id(), id, name))
+}
+
 #[test]
 fn standard_tests() {
     // Run the standard Forth test suite (with adaptations)
     // See: https://forth-standard.org/standard/testsuite
     let mut forth = Forth::new(10);
     assert!(forth.eval_file("include/std.f").is_ok());
-    assert!(forth.eval_file("include/testsuite.f").is_ok());
+    assert_eq!(forth.eval_file("include/testsuite.f"), Err(Error::Bye(0)));
 }
 
 #[test_case("0", &[], &[0]; "zero")]
@@ -90,6 +102,22 @@ fn standard_tests() {
 #[test_case("begin 1 + dup 10 > if leave then again", &[0], &[11]; "begin again")]
 #[test_case("do i loop", &[5, 0], &[0, 1, 2, 3, 4]; "do loop")]
 #[test_case("3 0 do 2 0 do j i loop loop", &[], &[0, 0, 0, 1, 1, 0, 1, 1, 2, 0, 2, 1]; "nested do loop")]
+#[test_case("source swap drop", &[], &[16]; "source length")]
+#[test_case("source drop @", &[], &['s' as Int]; "source address")]
+#[test_case(">in @", &[], &[5]; "position in input")]
+#[test_case("source swap drop >in ! 42", &[], &[]; "skip the rest of input")]
+#[test_case("parse-name hello swap drop", &[], &[5]; "parse name length")]
+#[test_case("parse-name   hello drop @", &[], &['h' as Int]; "parse name skips whitespaces")]
+#[test_case("parse-name", &[], &[INPUT_BUFFER + 10, 0]; "parse name at end of input")]
+#[test_case("char ) parse abc) swap drop 42", &[], &[3, 42]; "parse until delimiter")]
+#[test_case("char ) parse abc", &[], &[INPUT_BUFFER + 13, 3]; "parse until end of line")]
+#[test_case("32 word   hello @", &[], &[5]; "word counted string length")]
+#[test_case("32 word hello 1+ @", &[], &['h' as Int]; "word counted string content")]
+#[test_case("char , word ,,ab, @", &[], &[2]; "word with delimiter")]
+#[test_case("refill", &[], &[FALSE]; "refill at end of input")]
+#[test_case("refill drop 5\n7", &[], &[TRUE, 7]; "refill skips the rest of the line")]
+#[test_case("0 abort\" oops!\" 42", &[], &[42]; "abort with message on false flag")]
+#[test_case(": name parse-name swap drop ; name foo", &[], &[3]; "parsing word")]
 fn eval_string(word: &str, init_stack: &[i32], expected_stack: &[i32]) {
     let expected_stack = expected_stack.to_vec();
     let init_stack = init_stack.to_vec();
@@ -133,7 +161,11 @@ fn eval_string(word: &str, init_stack: &[i32], expected_stack: &[i32]) {
 fn underflow_for_empty_stack(word: &str) {
     let mut forth = Forth::new(10);
     forth.data_stack.clear();
-    assert_eq!(forth.eval_string(word), Err(StackUnderflow), "empty stack");
+    assert_eq!(
+        forth.eval_string(word).map_err(Error::into_kind),
+        Err(StackUnderflow),
+        "empty stack"
+    );
 }
 
 #[test_case("+"; "add")]
@@ -159,7 +191,10 @@ fn underflow_for_empty_stack(word: &str) {
 fn underflow_for_one_value_on_stack(word: &str) {
     let mut forth = Forth::new(10);
     forth.data_stack = vec![1];
-    assert_eq!(forth.eval_string(word), Err(StackUnderflow),);
+    assert_eq!(
+        forth.eval_string(word).map_err(Error::into_kind),
+        Err(StackUnderflow),
+    );
 }
 
 #[test_case("*/"; "mul div")]
@@ -169,7 +204,10 @@ fn underflow_for_one_value_on_stack(word: &str) {
 fn underflow_for_two_value_on_stack(word: &str) {
     let mut forth = Forth::new(10);
     forth.data_stack = vec![1, 2];
-    assert_eq!(forth.eval_string(word), Err(StackUnderflow),);
+    assert_eq!(
+        forth.eval_string(word).map_err(Error::into_kind),
+        Err(StackUnderflow),
+    );
 }
 
 #[test_case("1 0 /", DivisionByZero; "div division by zero")]
@@ -182,11 +220,13 @@ fn underflow_for_two_value_on_stack(word: &str) {
 #[test_case("0 if 0 else 1 0 / then", DivisionByZero; "if-then-else propagates errors on false branch")]
 #[test_case(": f 1 0 / . 2 2 + ; f", DivisionByZero; "function propagates errors")]
 #[test_case("begin 1 0 / again", DivisionByZero; "begin loop propagates errors")]
+#[test_case("-1 abort\" oops!\"", Error::AbortWithMessage("oops!".into()); "abort with message")]
+#[test_case(": f 5 > abort\" too big\" ; 10 f", Error::AbortWithMessage("too big".into()); "abort with message in function")]
 #[test_case("1 2 */", StackUnderflow; "mul div not enough elements")]
 #[test_case("1 2 */mod", StackUnderflow; "mul div mod not enough elements")]
 fn errors(code: &str, err: Error) {
     let mut forth = Forth::new(10);
-    assert_eq!(forth.eval_string(code), Err(err));
+    assert_eq!(forth.eval_string(code).map_err(Error::into_kind), Err(err));
 }
 
 #[test]
@@ -205,7 +245,7 @@ fn constants() {
 
     assert_eq!(
         Err(Error::Redefined("x".into())),
-        forth.eval_string("0 constant x"),
+        forth.eval_string("0 constant x").map_err(Error::into_kind),
         "errors on redefinition"
     );
 }
@@ -227,6 +267,219 @@ fn variables() {
     assert!(forth.eval_string("17 y !").is_err());
 }
 
+#[test]
+fn heap() {
+    let mut forth = Forth::new(10);
+    assert!(forth
+        .eval_string(&format!("{} constant heap", HEAP))
+        .is_ok());
+
+    assert!(forth.eval_string("3 allocate").is_ok());
+    assert_eq!(forth.data_stack, vec![HEAP, 0]);
+    assert!(forth.eval_string("drop 2 allocate drop").is_ok());
+    assert_eq!(forth.data_stack, vec![HEAP, HEAP + 3]);
+    assert!(forth
+        .eval_string("clearstack 42 heap 2 + ! 7 heap 3 + ! heap 2 + @")
+        .is_ok());
+    assert_eq!(forth.data_stack, vec![42]);
+    assert_eq!(
+        forth.eval_string("heap 5 + @").map_err(Error::into_kind),
+        Err(InvalidAddress)
+    );
+
+    // the block does not fit in the gap, so it is moved, but keeps its content
+    assert!(forth.eval_string("clearstack heap 4 resize").is_ok());
+    assert_eq!(forth.data_stack, vec![HEAP + 5, 0]);
+    assert!(forth.eval_string("clearstack heap 7 + @").is_ok());
+    assert_eq!(forth.data_stack, vec![42]);
+    assert_eq!(
+        forth.eval_string("heap @").map_err(Error::into_kind),
+        Err(InvalidAddress)
+    );
+
+    // the freed space is reused
+    assert!(forth
+        .eval_string("clearstack heap 3 + free 1 allocate")
+        .is_ok());
+    assert_eq!(forth.data_stack, vec![0, HEAP, 0]);
+    assert_eq!(
+        forth.eval_string("heap 3 + @").map_err(Error::into_kind),
+        Err(InvalidAddress)
+    );
+
+    assert!(forth.eval_string("clearstack heap 3 + free").is_ok());
+    assert_eq!(forth.data_stack, vec![-60], "double free");
+    assert!(forth.eval_string("clearstack -1 allocate").is_ok());
+    assert_eq!(forth.data_stack, vec![0, -59]);
+    assert!(forth.eval_string("clearstack heap 3 + 10 resize").is_ok());
+    assert_eq!(forth.data_stack, vec![HEAP + 3, -61]);
+}
+
+#[test]
+fn virtual_clock() {
+    let mut forth = Forth::new(10);
+    forth.set_clock(VirtualClock::new(Duration::from_secs(1_000_000_000)));
+
+    assert!(forth.eval_string("time&date").is_ok());
+    assert_eq!(forth.data_stack, vec![40, 46, 1, 9, 9, 2001]);
+    assert!(forth.eval_string("clearstack utime").is_ok());
+    assert_eq!(forth.data_stack, vec![-1530494976, 232830]);
+
+    assert!(forth
+        .eval_string("clearstack ticks 1500 ms ticks 2 ms ticks")
+        .is_ok());
+    assert_eq!(forth.data_stack, vec![0, 1500, 1502]);
+    assert!(forth.eval_string("clearstack time&date").is_ok());
+    assert_eq!(forth.data_stack, vec![41, 46, 1, 9, 9, 2001]);
+}
+
+#[test]
+fn random_numbers() {
+    let mut forth = Forth::new(10);
+
+    assert!(forth
+        .eval_string("42 seed random random 10 choose 10 choose")
+        .is_ok());
+    assert_eq!(forth.data_stack, vec![-1109970394, 686809907, 2, 3]);
+
+    assert!(forth
+        .eval_string("clearstack 42 seed random random 10 choose 10 choose")
+        .is_ok());
+    assert_eq!(
+        forth.data_stack,
+        vec![-1109970394, 686809907, 2, 3],
+        "the same seed gives the same numbers"
+    );
+
+    assert!(forth
+        .eval_string("clearstack 1000 0 do 3 choose loop")
+        .is_ok());
+    assert!(forth.data_stack.iter().all(|x| (0..3).contains(x)));
+    for x in 0..3 {
+        assert!(forth.data_stack.contains(&x));
+    }
+
+    assert!(forth.eval_string("0 choose").is_err());
+}
+
+#[test]
+fn bye() {
+    let mut forth = Forth::new(10);
+
+    assert_eq!(
+        forth.eval_string("1 2 bye 3").map_err(Error::into_kind),
+        Err(Error::Bye(0))
+    );
+    assert_eq!(forth.data_stack, vec![1, 2]);
+    assert_eq!(
+        forth
+            .eval_string(": f 42 (bye) ; f")
+            .map_err(Error::into_kind),
+        Err(Error::Bye(42))
+    );
+    assert!(
+        forth.eval_string("2 2 +").is_ok(),
+        "the interpreter still works"
+    );
+}
+
+#[test]
+fn deep_recursion() {
+    let mut forth = Forth::new(10);
+
+    let code = ": f dup 0 > if 1 - f 1 + then ;";
+    assert!(forth.eval_string(code).is_ok());
+    assert_eq!(
+        forth.eval_string("500000 f").map_err(Error::into_kind),
+        Err(Error::ReturnStackOverflow)
+    );
+    assert_eq!(forth.eval_string("50000 f"), Ok(()));
+    assert_eq!(forth.data_stack, vec![50000]);
+
+    forth.set_max_call_depth(1000);
+    assert_eq!(
+        forth.eval_string("2000 f").map_err(Error::into_kind),
+        Err(Error::ReturnStackOverflow)
+    );
+    assert!(forth.frames.is_empty());
+    assert!(forth.eval_string("900 f").is_ok());
+    assert_eq!(forth.data_stack, vec![900]);
+}
+
+#[test]
+fn tail_calls() {
+    let mut forth = Forth::new(10);
+    forth.set_max_call_depth(100);
+
+    assert!(forth
+        .eval_string(": countdown dup 0 > if 1 - countdown then ; 100000 countdown")
+        .is_ok());
+    assert_eq!(forth.data_stack, vec![0]);
+
+    assert!(forth
+        .eval_string(": f dup 0 > if 1 - f else drop 42 exit then ; : g f 1 ; 100000 g")
+        .is_ok());
+    assert_eq!(forth.data_stack, vec![0, 42, 1]);
+
+    let tail = forth.dictionary["countdown"];
+    match forth.get_word("countdown") {
+        Some(Expr::Function(code, _)) => assert_eq!(code.last(), Some(&Instr::TailCall(tail))),
+        other => panic!("expected function, got {:?}", other),
+    }
+    let f = forth.dictionary["f"];
+    match forth.get_word("g") {
+        Some(Expr::Function(code, _)) => assert_eq!(code[0], Instr::Call(f)),
+        other => panic!("expected function, got {:?}", other),
+    }
+}
+
+#[test]
+fn tail_call_from_loop() {
+    let mut forth = Forth::new(10);
+    // inlining would remove the call
+    forth.set_optimize(false);
+
+    assert!(forth
+        .eval_string(": f 7 ; : g 10 0 do i 5 = if f exit then loop ; g")
+        .is_ok());
+    assert_eq!(forth.data_stack, vec![7]);
+    assert!(forth.return_stack.is_empty());
+}
+
+#[test_case(": in i 10 * ; : out 3 0 do in exit loop ; out", &[0], true; "index optimized")]
+#[test_case(": in i 10 * ; : out 3 0 do in exit loop ; out", &[0], false; "index")]
+#[test_case(
+        ": in leave ; : out 3 0 do i in exit loop 99 ; : top 2 0 do out loop 7 ; top",
+        &[0, 99, 0, 99, 7],
+        true;
+        "leave optimized"
+    )]
+#[test_case(
+        ": in leave ; : out 3 0 do i in exit loop 99 ; : top 2 0 do out loop 7 ; top",
+        &[0, 99, 0, 99, 7],
+        false;
+        "leave"
+    )]
+fn call_before_exit_uses_loop(code: &str, expected: &[Int], optimize: bool) {
+    let mut forth = Forth::new(10);
+    forth.set_optimize(optimize);
+
+    assert!(forth.eval_string(code).is_ok());
+    assert_eq!(forth.data_stack, expected);
+    assert!(forth.return_stack.is_empty());
+}
+
+#[test]
+fn leave_from_called_word() {
+    let mut forth = Forth::new(10);
+
+    assert!(forth
+        .eval_string(": stop dup 3 = if leave then ; : f 10 0 do i stop loop ; f")
+        .is_ok());
+    assert_eq!(forth.data_stack, vec![0, 1, 2, 3]);
+    assert!(forth.return_stack.is_empty());
+}
+
 #[test]
 fn return_stack() {
     let mut forth = Forth::new(10);
@@ -375,6 +628,11 @@ fn return_stack() {
         &[Begin(vec![Word("while".into())])];
         "empty begin while"
     )]
+#[test_case(
+        "abort\" oops!\"",
+        &[AbortQuote("oops!".into())];
+        "abort with message"
+    )]
 #[test_case(
         "hello ( this is a comment ) world",
         &[Word("hello".into()), Word("world".into())];
@@ -398,8 +656,878 @@ fn parsing(input: &str, expected: &[Expr]) {
 #[test_case(".\" hello, world!"; "unclosed string")]
 #[test_case(".( hello, world!"; "unclosed instant print")]
 #[test_case("( foo bar baz"; "unclosed comment")]
+#[test_case("abort\" oops"; "unclosed abort message")]
 fn parsing_errors(input: &str) {
     let parser = Parser::from(input);
     let result: Result<Vec<Expr>, Error> = parser.collect();
     assert!(result.is_err());
 }
+
+#[test_case(
+        "1 2",
+        &[Instr::Push(1), Instr::Push(2)];
+        "simple body"
+    )]
+#[test_case(
+        "if 1 then 2",
+        &[Instr::If(2), Instr::Push(1), Instr::Push(2)];
+        "if then"
+    )]
+#[test_case(
+        "if 1 else 2 then",
+        &[Instr::If(3), Instr::Push(1), Instr::Else(4), Instr::Push(2)];
+        "if else then"
+    )]
+#[test_case(
+        "begin 1 again",
+        &[Instr::Begin(3), Instr::Push(1), Instr::Again(1)];
+        "begin again"
+    )]
+#[test_case(
+        "begin 1 until",
+        &[Instr::Begin(3), Instr::Push(1), Instr::Until(1)];
+        "begin until"
+    )]
+#[test_case(
+        "begin 1 while 2 repeat",
+        &[Instr::Begin(5), Instr::Push(1), Instr::While, Instr::Push(2), Instr::Repeat(1)];
+        "begin while repeat"
+    )]
+#[test_case(
+        "do 1 if leave then loop exit",
+        &[Instr::Do(5), Instr::Push(1), Instr::If(4), Instr::Leave, Instr::Loop(1), Instr::Exit];
+        "do loop with leave"
+    )]
+fn compiling(input: &str, expected: &[Instr]) {
+    let mut forth = Forth::new(10);
+    let body: Result<Vec<Expr>, Error> = Parser::from(input).collect();
+    assert_eq!(compile(&mut forth, &body.unwrap()), expected);
+}
+
+#[test]
+fn binding() {
+    let mut forth = Forth::new(10);
+
+    let body: Result<Vec<Expr>, Error> = Parser::from("dup 42 foo").collect();
+    let code = compile(&mut forth, &body.unwrap());
+    let dup = forth.dictionary["dup"];
+    let foo = forth.dictionary["foo"];
+    assert_eq!(
+        code,
+        vec![Instr::Call(dup), Instr::Push(42), Instr::Call(foo)]
+    );
+    assert!(forth.get_word("foo").is_none());
+    assert!(!forth.words().contains(&"foo".to_string()));
+
+    // forward references are bound when the word gets defined
+    assert!(forth.eval_string(": f g ; : g 1 ; f").is_ok());
+    assert_eq!(forth.data_stack, vec![1]);
+
+    // redefinitions do not change the already compiled code
+    assert!(forth.eval_string(": g 2 ;").is_err());
+    assert!(forth.eval_string("clearstack f g").is_ok());
+    assert_eq!(forth.data_stack, vec![1, 2]);
+
+    // values can be changed
+    assert!(forth
+        .eval_string("clearstack 5 value x : h x ; h 7 to x h")
+        .is_ok());
+    assert_eq!(forth.data_stack, vec![5, 7]);
+}
+
+#[test_case("1 2 +"; "simple body")]
+#[test_case("if 1 then"; "if then")]
+#[test_case("if 1 else 2 then 3"; "if else then")]
+#[test_case("if if 1 then then"; "nested if then")]
+#[test_case("if if 1 else 2 then else if 3 else 4 then then"; "nested if else then")]
+#[test_case("begin 1 again"; "begin again")]
+#[test_case("begin dup until"; "begin until")]
+#[test_case("begin dup while 1 - repeat"; "begin while repeat")]
+#[test_case("10 0 do i 5 > if leave then loop"; "do loop")]
+#[test_case(".\" hello\" exit"; "print and exit")]
+fn decompiling(body: &str) {
+    use crate::expressions::Expr::Function;
+
+    let mut forth = Forth::new(10);
+    assert!(forth.eval_string(&format!(": f {} ;", body)).is_ok());
+    match forth.get_word("f") {
+        Some(Function(_, source)) => assert_eq!(decompile(&forth, &source), body),
+        other => panic!("expected function, got {:?}", other),
+    }
+}
+
+#[test_case("2 3 + 4 *", "20"; "constant folding")]
+#[test_case("1 0 /", "1 0 /"; "division by zero is not folded")]
+#[test_case("5 negate 0=", "0"; "unary folding")]
+#[test_case("1 + 2 +", "3 +"; "adding constants")]
+#[test_case("3 -", "-3 +"; "subtracting constant")]
+#[test_case("swap drop over over drop drop", "nip 2dup 2drop"; "fused words")]
+#[test_case("dup if 1 + then 1 +", "dup if 1 + then 1 +"; "no rewrites across jump targets")]
+#[test_case("sq sq", "dup * dup *"; "inlining")]
+#[test_case("fact", "fact"; "recursive functions are not inlined")]
+#[test_case("early", "early"; "functions with exit are not inlined")]
+fn optimizing(body: &str, expected: &str) {
+    use crate::expressions::Expr::Function;
+
+    let mut forth = Forth::new(10);
+    assert!(forth
+        .eval_string(": sq dup * ; : fact dup 1 > if dup 1 - fact * then ; : early exit ;")
+        .is_ok());
+    assert!(forth.eval_string(&format!(": f {} ;", body)).is_ok());
+    match forth.get_word("f") {
+        Some(Function(code, source)) => {
+            assert_eq!(decompile(&forth, &code), expected);
+            assert_eq!(decompile(&forth, &source), body);
+        }
+        other => panic!("expected function, got {:?}", other),
+    }
+}
+
+#[test]
+fn optimizer_preserves_behavior() {
+    let code = ": sq dup * ;
+        : f 2 3 + sq swap drop 10 0 do i 2 * + loop 1 - ;
+        : g begin dup while 1 - 0 swap repeat drop ;
+        7 f 3 g";
+    let mut optimized = Forth::new(10);
+    assert!(optimized.eval_string(code).is_ok());
+    let mut plain = Forth::new(10);
+    plain.set_optimize(false);
+    assert!(plain.eval_string(code).is_ok());
+    assert_eq!(optimized.data_stack, plain.data_stack);
+    assert_eq!(optimized.data_stack, vec![114, 0, 0, 0]);
+
+    match plain.get_word("f") {
+        Some(Expr::Function(code, source)) => assert!(Rc::ptr_eq(&code, &source)),
+        other => panic!("expected function, got {:?}", other),
+    }
+}
+
+#[test]
+fn native_words() {
+    use std::cell::Cell;
+
+    let mut forth = Forth::new(10);
+    let counter = Rc::new(Cell::new(0));
+    let calls = counter.clone();
+    assert!(forth
+        .define_native("count", move |forth| {
+            calls.set(calls.get() + 1);
+            forth.stack_push(calls.get());
+            Ok(())
+        })
+        .is_ok());
+    let mut total = 0;
+    assert!(forth
+        .define_native("total", move |forth| {
+            total += forth.stack_pop()?;
+            forth.stack_push(total);
+            Ok(())
+        })
+        .is_ok());
+
+    assert!(forth.eval_string(": f 3 0 do count loop ; f total").is_ok());
+    assert_eq!(counter.get(), 3);
+    assert_eq!(forth.data_stack, vec![1, 2, 3]);
+    assert_eq!(forth.eval_string("5 total"), Ok(()));
+    assert_eq!(forth.data_stack, vec![1, 2, 3, 8]);
+    assert_eq!(forth.eval_string("total"), Ok(()));
+    assert_eq!(forth.data_stack, vec![1, 2, 3, 16]);
+
+    forth.data_stack.clear();
+    assert_eq!(
+        forth.eval_string("total").map_err(Error::into_kind),
+        Err(StackUnderflow)
+    );
+    assert_eq!(
+        forth.define_native("count", |_| Ok(())),
+        Err(Error::Redefined("count".into()))
+    );
+    assert_eq!(forth.eval_string("count f"), Ok(()));
+    assert_eq!(forth.data_stack, vec![4, 5, 6]);
+
+    assert!(forth
+        .define_native("recurse-native", |forth| forth
+            .eval_string("recurse-native"))
+        .is_ok());
+    assert!(matches!(
+        forth
+            .eval_string("recurse-native")
+            .map_err(Error::into_kind),
+        Err(Error::CustomError(_))
+    ));
+}
+
+#[test_case("1 2 . .", "2 1 "; "dot")]
+#[test_case("65 emit 66 emit cr", "AB\n"; "emit and cr")]
+#[test_case("42 5 .r", "   42"; "right align")]
+#[test_case("1 2 3 .s", " <3> 1 2 3"; "print stack")]
+#[test_case(".\" hello\"", "hello"; "print string")]
+#[test_case(".( hello) 1 .", "hello1 "; "instant print")]
+#[test_case(": f .( compiled) 1 . ; 2 . f", "compiled2 1 "; "instant print while compiling")]
+#[test_case("variable x 7 x ! x 1 dump", "7"; "dump")]
+#[test_case(": f 1 + ; see f", ": f 1 + ;"; "see")]
+#[test_case(": f 2 3 + ; see f", ": f 2 3 + ;\n\\ optimized: 5"; "see optimized")]
+#[test_case("see if", "<special word: if>"; "see special word")]
+fn printing(code: &str, expected: &str) {
+    let mut forth = Forth::new(10);
+    assert_eq!(forth.eval_string_captured(code), (Ok(()), expected.into()));
+}
+
+#[test]
+fn output_sink() {
+    use std::cell::RefCell;
+    use std::io::Write;
+
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buffer = Rc::new(RefCell::new(Vec::new()));
+    let mut forth = Forth::new(10);
+    forth.set_output(Shared(buffer.clone()));
+    assert!(forth.eval_string("1 . .\" a\" cr").is_ok());
+    assert_eq!(forth.eval_string_captured("2 . 3"), (Ok(()), "2 ".into()));
+    let (result, output) = forth.eval_string_captured(". foo");
+    assert_eq!(
+        result.map_err(Error::into_kind),
+        Err(Error::UnknownWord("foo".into()))
+    );
+    assert_eq!(output, "3 ");
+    assert!(forth.eval_string("4 .").is_ok());
+    assert_eq!(buffer.borrow().as_slice(), b"1 a\n4 ");
+}
+
+#[test]
+fn embedding_api() {
+    let mut forth = Forth::new(10);
+    forth.stack_extend([1, 2, 3, 4]);
+    assert_eq!(forth.stack_pop_n::<3>(), Ok([2, 3, 4]));
+    assert_eq!(forth.stack_pop_n::<2>(), Err(StackUnderflow));
+    assert_eq!(forth.data_stack, vec![1]);
+    assert_eq!(forth.stack_pop_n::<0>(), Ok([]));
+
+    assert!(forth.eval_string("variable x 42 x !").is_ok());
+    let Some(Expr::Value(x)) = forth.get_word("x") else {
+        panic!("expected x to be a constant");
+    };
+    assert_eq!(forth.read_memory(x), Ok(42));
+    assert_eq!(forth.write_memory(x, 7), Ok(()));
+    assert_eq!(forth.eval_string("x @"), Ok(()));
+    assert_eq!(forth.stack_pop(), Ok(7));
+    assert_eq!(forth.read_memory(x + 100), Err(InvalidAddress));
+
+    assert_eq!(forth.define_word("answer", Expr::Value(42)), Ok(()));
+    assert!(forth
+        .eval_string(": double 2 * ; : early 1 exit 2 ;")
+        .is_ok());
+    assert_eq!(forth.call_word("answer"), Ok(()));
+    assert_eq!(forth.call_word("double"), Ok(()));
+    assert_eq!(forth.call_word("early"), Ok(()));
+    assert_eq!(forth.data_stack, vec![1, 84, 1]);
+    assert_eq!(
+        forth.call_word("missing").map_err(Error::into_kind),
+        Err(Error::UnknownWord("missing".into()))
+    );
+    assert!(forth.eval_string(": later undefined ;").is_ok());
+    assert_eq!(
+        forth.call_word("undefined").map_err(Error::into_kind),
+        Err(Error::UnknownWord("undefined".into()))
+    );
+
+    let words = forth.words();
+    assert!(words.contains(&"double".to_string()));
+    assert!(!words.contains(&"undefined".to_string()));
+    assert!(words.windows(2).all(|pair| pair[0] <= pair[1]));
+
+    assert!(forth.eval_string("5 >r").is_ok());
+    assert_eq!(forth.return_stack(), &[5]);
+
+    let parsed = Parser::from("1 dup").collect::<Result<Vec<_>, _>>();
+    assert_eq!(parsed, Ok(vec![Word("1".into()), Word("dup".into())]));
+}
+
+#[test_case("exit", &[2] ; "exit")]
+#[test_case("leave", &[2] ; "leave")]
+#[test_case("quit", &[] ; "quit")]
+fn call_word_with_early_exit(word: &str, return_stack: &[Int]) {
+    let mut forth = Forth::new(10);
+    assert!(forth.eval_string("1 2 >r").is_ok());
+    assert_eq!(forth.call_word(word), Ok(()));
+    assert_eq!(forth.data_stack, vec![1]);
+    assert_eq!(forth.return_stack(), return_stack);
+}
+
+#[test]
+fn define_invalid_function() {
+    let mut forth = Forth::new(10);
+    let code: Rc<[Instr]> = vec![Instr::Loop(0)].into();
+    assert!(forth
+        .define_word("bad", Expr::Function(code.clone(), code.clone()))
+        .is_err());
+    assert_eq!(forth.get_word("bad"), None);
+    assert_eq!(
+        forth.eval_string("bad").map_err(Error::into_kind),
+        Err(Error::UnknownWord("bad".into()))
+    );
+
+    let code: Rc<[Instr]> = vec![Instr::Expr(Expr::Function(code.clone(), code))].into();
+    assert!(forth
+        .define_word("nested", Expr::Function(code.clone(), code))
+        .is_err());
+
+    let code: Rc<[Instr]> = vec![Instr::Push(1), Instr::Push(2)].into();
+    assert!(forth
+        .define_word("good", Expr::Function(code.clone(), code))
+        .is_ok());
+    assert!(forth.call_word("good").is_ok());
+    assert_eq!(forth.data_stack, vec![1, 2]);
+}
+
+#[test]
+fn instruction_limit() {
+    let mut forth = Forth::new(10);
+    forth.set_fuel(Some(1000));
+    assert_eq!(
+        forth.eval_string("begin again").map_err(Error::into_kind),
+        Err(Error::OutOfFuel)
+    );
+    assert_eq!(forth.fuel(), Some(0));
+    assert!(forth.frames.is_empty());
+    assert_eq!(
+        forth.eval_string("1").map_err(Error::into_kind),
+        Err(Error::OutOfFuel)
+    );
+
+    forth.set_fuel(Some(100));
+    assert_eq!(forth.eval_string(": f 0 10 0 do i + loop ; f"), Ok(()));
+    assert_eq!(forth.data_stack, vec![45]);
+    assert!(forth.fuel() < Some(100));
+
+    forth.set_fuel(None);
+    assert_eq!(forth.eval_string("100000 0 do loop"), Ok(()));
+    assert_eq!(forth.fuel(), None);
+}
+
+#[test_case("100000 allot"; "allot")]
+#[test_case("100 allot 1 , 2 , 3 ,"; "comma")]
+#[test_case("100 allot variable a variable b variable c"; "variables")]
+#[test_case("here 102 allot 102 + 1 swap !"; "store at the end")]
+#[test_case("50 allocate drop 60 allocate"; "allocate")]
+#[test_case("50 allocate drop 200 resize"; "resize")]
+fn memory_limit(code: &str) {
+    let mut forth = Forth::new(10);
+    forth.set_max_memory(102);
+    assert_eq!(
+        forth.eval_string(code).map_err(Error::into_kind),
+        Err(Error::MemoryLimitExceeded)
+    );
+}
+
+#[test]
+fn memory_within_limit() {
+    let mut forth = Forth::new(10);
+    forth.set_max_memory(100);
+    assert_eq!(
+        forth.eval_string("50 allot 40 allocate drop 10 resize drop -5 allot"),
+        Ok(())
+    );
+    assert_eq!(forth.memory.len() + forth.heap.size(), 60);
+}
+
+#[test]
+fn stack_limits() {
+    let mut forth = Forth::new(10);
+    forth.set_max_stack_depth(5);
+    assert_eq!(forth.eval_string("1 2 3 4 5"), Ok(()));
+    assert_eq!(
+        forth.eval_string("6").map_err(Error::into_kind),
+        Err(Error::StackOverflow)
+    );
+    assert_eq!(
+        forth
+            .eval_string(": f begin 1 again ; f")
+            .map_err(Error::into_kind),
+        Err(Error::StackOverflow)
+    );
+    assert!(forth.frames.is_empty());
+
+    forth.set_max_return_stack_depth(3);
+    assert_eq!(forth.eval_string("1 >r 2 >r 3 >r r> r> r> + +"), Ok(()));
+    assert_eq!(forth.data_stack, vec![6]);
+    assert_eq!(
+        forth
+            .eval_string(": g begin 1 >r again ; g")
+            .map_err(Error::into_kind),
+        Err(Error::ReturnStackOverflow)
+    );
+}
+
+#[test]
+fn interrupting() {
+    use std::sync::atomic::Ordering;
+
+    let mut forth = Forth::new(10);
+    let handle = forth.interrupt_handle();
+    let counter = Rc::new(std::cell::Cell::new(0));
+    let calls = counter.clone();
+    assert!(forth
+        .define_native("tick", move |_| {
+            calls.set(calls.get() + 1);
+            if calls.get() == 100 {
+                handle.store(true, Ordering::Relaxed);
+            }
+            Ok(())
+        })
+        .is_ok());
+
+    assert_eq!(
+        forth
+            .eval_string(": f 0 begin tick 1 + again ; 1 2 f")
+            .map_err(Error::into_kind),
+        Err(Error::Interrupted)
+    );
+    assert_eq!(counter.get(), 100);
+    assert!(forth.frames.is_empty());
+    assert!(!forth.interrupt_handle().load(Ordering::Relaxed));
+
+    // the session is still usable
+    assert_eq!(forth.eval_string("1 2 +"), Ok(()));
+    assert_eq!(forth.data_stack, vec![3]);
+
+    let handle = forth.interrupt_handle();
+    let thread = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        handle.store(true, Ordering::Relaxed);
+    });
+    assert_eq!(
+        forth
+            .eval_string("10 0 do begin 1 drop again loop")
+            .map_err(Error::into_kind),
+        Err(Error::Interrupted)
+    );
+    thread.join().unwrap();
+    assert!(forth.return_stack().is_empty());
+}
+
+#[test]
+fn error_locations() {
+    use crate::reader::Location;
+
+    let mut forth = Forth::new(10);
+    // the inlined functions and tail calls are not visible in the call chain
+    forth.set_optimize(false);
+    let code = ": div / 1 ;\n: f ( n -- ) 0 div + ;\n: g\n  1 f 2 ;\n\n  ( comment ) 5 g";
+    let err = forth.eval_string(code).unwrap_err();
+    assert_eq!(err.kind(), &DivisionByZero);
+    assert_eq!(
+        err.location(),
+        Some(&Location {
+            file: None,
+            line: 6,
+            column: 17
+        })
+    );
+    assert_eq!(err.call_chain(), ["/", "div", "f", "g"]);
+    assert_eq!(
+        err.to_string(),
+        "<input>:6:17: division by zero\n  in /\n  in div\n  in f\n  in g"
+    );
+
+    let err = forth.eval_string("1 2\n  : h 1 2 +").unwrap_err();
+    assert_eq!(err.to_string(), "<input>:2:3: missing ';'");
+    assert!(err.call_chain().is_empty());
+
+    let err = forth.eval_string("  missing").unwrap_err();
+    assert_eq!(err.to_string(), "<input>:1:3: missing is an unknown word");
+    // the errors with different locations are not equal, even if they are of the same kind
+    assert_ne!(forth.eval_string("missing").unwrap_err(), err);
+    assert_eq!(forth.eval_string("missing").unwrap_err().kind(), err.kind());
+
+    // the control flow is not traced
+    assert_eq!(
+        forth.eval_string(": quit-with 1 (bye) ; quit-with"),
+        Err(Error::Bye(1))
+    );
+
+    let path = temp_path("error-locations.f");
+    std::fs::write(&path, "1 2 +\n: k 0 0 / ;\nk\n").unwrap();
+    let path = path.to_str().unwrap();
+    let err = forth.eval_string(&format!("include {}", path)).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!("{}:3:1: division by zero\n  in /\n  in k", path)
+    );
+}
+
+#[test]
+fn error_call_chain_is_limited() {
+    let mut forth = Forth::new(10);
+    forth.set_max_call_depth(100);
+    let err = forth
+        .eval_string(": f dup 0 > if 1 - f 1 + then ; 1000 f")
+        .unwrap_err();
+    assert_eq!(err.kind(), &Error::ReturnStackOverflow);
+    assert_eq!(err.call_chain().len(), 16);
+    assert!(err.call_chain().iter().all(|word| word == "f"));
+}
+
+#[test_case(ErrorRecovery::Clear, &[]; "clear")]
+#[test_case(ErrorRecovery::Preserve, &[1, 2, 3, 4]; "preserve")]
+#[test_case(ErrorRecovery::Restore, &[1, 2]; "restore")]
+fn error_recovery(policy: ErrorRecovery, expected: &[Int]) {
+    let mut forth = Forth::new(10);
+    forth.set_error_recovery(policy);
+    assert!(forth.eval_string("1 2").is_ok());
+    assert_eq!(
+        forth
+            .eval_string(": f 10 0 do i 5 >r 3 = if 3 4 0 / then r> drop loop ; f")
+            .map_err(Error::into_kind),
+        Err(DivisionByZero)
+    );
+    assert_eq!(forth.data_stack, expected);
+    assert!(forth.return_stack().is_empty());
+    assert!(forth.frames.is_empty());
+
+    // the interpreter is usable after the error
+    assert!(forth.eval_string("3 0 do i loop").is_ok());
+    assert_eq!(forth.data_stack[expected.len()..], [0, 1, 2]);
+    assert!(forth.return_stack().is_empty());
+}
+
+#[test]
+fn error_recovery_in_nested_evaluation() {
+    let mut forth = Forth::new(10);
+    forth.set_error_recovery(ErrorRecovery::Restore);
+    assert!(forth
+        .define_native("try", |forth| {
+            let result = forth.eval_string("1 >r 2 3 missing");
+            forth.stack_push(from_bool(result.is_err()));
+            Ok(())
+        })
+        .is_ok());
+    assert!(forth.eval_string("7 >r 5 try r>").is_ok());
+    assert_eq!(forth.data_stack, vec![5, TRUE, 7]);
+    assert!(forth.return_stack().is_empty());
+}
+
+#[test]
+fn atomic_evaluation() {
+    let mut forth = Forth::new(10);
+    assert!(forth.eval_string("1 2 variable x 5 x ! 3 value y").is_ok());
+    let words = forth.words();
+    let memory = forth.memory.clone();
+
+    let code =
+        "3 >r : f 1 ; variable z 10 allot 7 x ! 4 to y 100 allocate drop drop : y 0 ; missing";
+    assert!(forth.eval_string_atomic(code).is_err());
+    assert_eq!(forth.data_stack, vec![1, 2]);
+    assert!(forth.return_stack().is_empty());
+    assert_eq!(forth.words(), words);
+    assert_eq!(forth.memory, memory);
+    assert_eq!(forth.heap.size(), 0);
+    assert_eq!(
+        forth.eval_string_captured("x @ . y ."),
+        (Ok(()), "5 3 ".into())
+    );
+    assert_eq!(
+        forth.eval_string("f").map_err(Error::into_kind),
+        Err(Error::UnknownWord("f".into()))
+    );
+
+    // on success the changes stay
+    assert!(forth.eval_string_atomic(": f 42 ; 7 x !").is_ok());
+    assert_eq!(
+        forth.eval_string_captured("f . x @ ."),
+        (Ok(()), "42 7 ".into())
+    );
+
+    // terminating with `bye` is not a failure
+    assert_eq!(
+        forth.eval_string_atomic(": foo 42 ; 1 2 bye"),
+        Err(Error::Bye(0))
+    );
+    assert_eq!(forth.data_stack, vec![1, 2]);
+    assert!(forth.eval_string("foo").is_ok());
+    assert_eq!(forth.data_stack, vec![1, 2, 42]);
+}
+
+#[test]
+fn images() {
+    let mut forth = Forth::new(10);
+    let double = forth.define_native("double", |forth| {
+        let value = forth.stack_pop()?;
+        forth.stack_push(value * 2);
+        Ok(())
+    });
+    assert!(double.is_ok());
+    forth.set_max_memory(1000);
+    forth.set_error_recovery(ErrorRecovery::Preserve);
+    let code = "
+        : sq dup * ;
+        : count-down begin dup . 1- dup 0= until drop ;
+        : quad sq double sq ;
+        variable x 42 x !
+        5 value y
+        10 allocate drop constant block 7 block 3 + !
+        : later undefined ;
+        1 2 3
+    ";
+    assert!(forth.eval_string(code).is_ok());
+    let mut image = Vec::new();
+    assert!(forth.write_image(&mut image).is_ok());
+
+    let mut other = Forth::new(10);
+    let double = other.define_native("double", |forth| {
+        let value = forth.stack_pop()?;
+        forth.stack_push(value * 2);
+        Ok(())
+    });
+    assert!(double.is_ok());
+    assert!(other.read_image(image.as_slice()).is_ok());
+    assert!(other.data_stack.is_empty());
+    assert_eq!(other.words(), forth.words());
+    assert_eq!(other.max_memory, 1000);
+    assert_eq!(other.error_recovery, ErrorRecovery::Preserve);
+    assert_eq!(
+        other.eval_string_captured("3 sq . 3 count-down 3 quad . x @ . y . block 3 + @ ."),
+        (Ok(()), "9 3 2 1 324 42 5 7 ".into())
+    );
+    assert_eq!(
+        other.eval_string_captured("see sq"),
+        forth.eval_string_captured("see sq")
+    );
+    // the words used before being defined are still bound to their slots
+    assert!(other.eval_string(": undefined 11 ; later").is_ok());
+    assert_eq!(other.data_stack, vec![11]);
+
+    // the native function needs to be defined
+    let mut other = Forth::new(10);
+    assert!(matches!(
+        other.read_image(image.as_slice()),
+        Err(Error::InvalidImage(_))
+    ));
+    assert!(other.eval_string("sq").is_err());
+}
+
+#[test_case(true ; "optimized")]
+#[test_case(false ; "not optimized")]
+fn image_of_standard_tests(optimize: bool) {
+    let mut forth = Forth::new(10);
+    forth.set_optimize(optimize);
+    assert!(forth.eval_file("include/std.f").is_ok());
+    assert_eq!(forth.eval_file("include/testsuite.f"), Err(Error::Bye(0)));
+    let mut image = Vec::new();
+    assert!(forth.write_image(&mut image).is_ok());
+
+    let mut other = Forth::new(10);
+    assert!(other.read_image(image.as_slice()).is_ok());
+    assert_eq!(other.words(), forth.words());
+}
+
+#[test_case(vec![Instr::Loop(0)] ; "loop without do")]
+#[test_case(vec![Instr::Begin(2), Instr::Loop(1)] ; "loop closing begin")]
+#[test_case(vec![Instr::Do(2), Instr::Push(1)] ; "unclosed do")]
+#[test_case(vec![Instr::Do(3), Instr::Push(1), Instr::Loop(0)] ; "loop target")]
+#[test_case(vec![Instr::Do(5), Instr::Push(1), Instr::Loop(1)] ; "do target")]
+#[test_case(vec![Instr::While] ; "while outside begin")]
+#[test_case(vec![Instr::If(5)] ; "if target out of code")]
+#[test_case(vec![Instr::If(0)] ; "backward if")]
+#[test_case(vec![Instr::If(2), Instr::Do(4), Instr::Push(1), Instr::Loop(2)] ; "if into loop")]
+#[test_case(vec![Instr::Call(100_000)] ; "unknown slot")]
+#[test_case(vec![Instr::Do(3), Instr::TailCall(0), Instr::Loop(1)] ; "tail call inside loop")]
+fn invalid_code_in_image(code: Vec<Instr>) {
+    let mut forth = Forth::new(10);
+    assert!(forth.eval_string(": f ;").is_ok());
+    let slot = forth.dictionary["f"];
+    let code: Rc<[Instr]> = code.into();
+    forth.slots[slot].1 = Some(Expr::Function(code.clone(), code));
+    let mut image = Vec::new();
+    assert!(forth.write_image(&mut image).is_ok());
+
+    let mut other = Forth::new(10);
+    assert!(matches!(
+        other.read_image(image.as_slice()),
+        Err(Error::InvalidImage(_))
+    ));
+}
+
+#[test]
+fn image_with_unknown_callable() {
+    fn answer(forth: &mut Forth) -> Result<(), Error> {
+        forth.stack_push(42);
+        Ok(())
+    }
+
+    let mut forth = Forth::new(10);
+    assert!(forth.define_word("answer", Expr::Callable(answer)).is_ok());
+    let mut image = Vec::new();
+    assert!(matches!(
+        forth.write_image(&mut image),
+        Err(Error::InvalidImage(_))
+    ));
+    assert!(image.is_empty());
+}
+
+#[test_case(b"" ; "empty")]
+#[test_case(b"FRTX\x01\x00\x00\x00" ; "magic")]
+#[test_case(b"FRTH\x02\x00\x00\x00" ; "version")]
+#[test_case(b"FRTH\x01\x00\x00\x00\x01" ; "truncated")]
+#[test_case(&image_with_heap(&[(Int::MIN, 1)]) ; "heap block at negative address")]
+#[test_case(&image_with_heap(&[(HEAP - 1, 1)]) ; "heap block before heap")]
+#[test_case(&image_with_heap(&[(INPUT_BUFFER, 0)]) ; "heap block after heap")]
+#[test_case(&image_with_heap(&[(INPUT_BUFFER - 1, 2)]) ; "heap block crossing heap end")]
+#[test_case(&image_with_heap(&[(HEAP, 2), (HEAP + 1, 1)]) ; "overlapping heap blocks")]
+#[test_case(&image_with_heap(&[(HEAP + 1, 1), (HEAP, 2)]) ; "unordered overlapping heap blocks")]
+#[test_case(&image_with_heap(&[(HEAP, 0), (HEAP, 1)]) ; "heap blocks with same address")]
+fn invalid_images(image: &[u8]) {
+    let mut forth = Forth::new(10);
+    assert!(matches!(
+        forth.read_image(image),
+        Err(Error::InvalidImage(_))
+    ));
+}
+
+/// The image of a new interpreter with the heap blocks of the sizes at the addresses.
+fn image_with_heap(blocks: &[(Int, usize)]) -> Vec<u8> {
+    let mut image = Vec::new();
+    assert!(Forth::new(10).write_image(&mut image).is_ok());
+    // the heap is saved at the end, as the number of the blocks followed by the blocks
+    image.truncate(image.len() - 8);
+    image.extend_from_slice(&(blocks.len() as u64).to_le_bytes());
+    for (addr, size) in blocks {
+        image.extend_from_slice(&addr.to_le_bytes());
+        image.extend_from_slice(&(*size as u64).to_le_bytes());
+        image.extend_from_slice(&vec![0; size * 4]);
+    }
+    image
+}
+
+#[test_case(&[] ; "no blocks")]
+#[test_case(&[(HEAP, 0), (HEAP + 1, 2), (INPUT_BUFFER - 1, 1)] ; "adjacent blocks")]
+#[test_case(&[(HEAP + 10, 1), (HEAP, 3)] ; "unordered blocks")]
+fn valid_heap_in_image(blocks: &[(Int, usize)]) {
+    let mut forth = Forth::new(10);
+    assert!(forth.read_image(image_with_heap(blocks).as_slice()).is_ok());
+    assert_eq!(forth.heap.blocks().count(), blocks.len());
+    assert!(forth.eval_string("1 allocate").is_ok());
+}
+
+#[test]
+fn image_words() {
+    let path = temp_path("image-words.img");
+    let path = path.to_str().unwrap();
+
+    let mut forth = Forth::new(10);
+    assert!(forth.words().contains(&"save-image".into()));
+    assert!(forth.words().contains(&"load-image".into()));
+    assert_eq!(
+        forth.eval_string_captured("see load-image"),
+        (Ok(()), "<special word: load-image>".into())
+    );
+    let code = format!(": answer 42 ; save-image {} : answer 0 ;", path);
+    assert!(forth.eval_string(&code).is_err());
+    assert!(forth
+        .eval_string(&format!("load-image {} answer", path))
+        .is_ok());
+    assert_eq!(forth.data_stack, vec![42]);
+
+    // the running code cannot be replaced
+    let code = format!(": reload load-image {} ; reload", path);
+    assert!(forth.eval_string(&code).is_err());
+    assert!(forth.eval_string("answer").is_ok());
+    assert_eq!(forth.data_stack, vec![42]);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn prelude() {
+    let mut forth = Forth::new(10);
+    assert!(forth.eval_string("1 2 min").is_err());
+
+    let mut forth = Forth::with_prelude(10);
+    assert!(forth.data_stack.is_empty());
+    assert_eq!(
+        forth.eval_string_captured("1 2 2dup min . max . 3 spaces variable x 5 x ! x ?"),
+        (Ok(()), "1 2    5 ".into())
+    );
+
+    // the prelude is compiled with the current configuration
+    let mut forth = Forth::new(10);
+    forth.set_optimize(false);
+    assert!(forth.load_prelude().is_ok());
+    assert_eq!(
+        forth.eval_string_captured("see spaces"),
+        (Ok(()), ": spaces 0 do space loop ;".into())
+    );
+}
+
+#[test_case(|forth| assert!(forth.load_prelude().is_ok()) ; "loaded twice")]
+#[test_case(|forth| assert!(forth.eval_string(": min 0 ;").is_ok()) ; "defined min")]
+#[test_case(|forth| assert!(forth.eval_string(": ? 0 ;").is_ok()) ; "defined question mark")]
+#[test_case(|forth| forth.set_fuel(Some(10)) ; "out of fuel")]
+fn prelude_fails(setup: fn(&mut Forth)) {
+    let mut forth = Forth::new(10);
+    setup(&mut forth);
+    let words = forth.words();
+    assert!(forth.load_prelude().is_err());
+    assert_eq!(forth.words(), words);
+}
+
+#[test]
+fn command_line_arguments() {
+    let mut forth = Forth::with_prelude(10);
+    assert_eq!(forth.eval_string_captured("argc ."), (Ok(()), "0 ".into()));
+
+    forth.set_arguments(["script.f".into(), "żółw".into(), "".into(), "-x".into()]);
+    let code = ": show argc 0 do i arg type 124 emit loop ; show 4 arg . .";
+    assert_eq!(
+        forth.eval_string_captured(code),
+        (Ok(()), "script.f|żółw||-x|0 0 ".into())
+    );
+    assert_eq!(
+        forth
+            .eval_string("1 arg drop 1 swap !")
+            .map_err(Error::into_kind),
+        Err(InvalidAddress)
+    );
+}
+
+#[test]
+fn shebang() {
+    let mut forth = Forth::new(10);
+    assert!(forth.eval_string("#!/usr/bin/env forthrs\n1 2").is_ok());
+    assert_eq!(forth.data_stack, vec![1, 2]);
+    assert!(forth.eval_string("#!/usr/bin/env forthrs").is_ok());
+
+    // only the first line can be skipped
+    assert_eq!(
+        forth
+            .eval_string("1 #!/usr/bin/env")
+            .map_err(Error::into_kind),
+        Err(Error::UnknownWord("#!/usr/bin/env".into()))
+    );
+}
+
+#[test_case(": f 1 +", true ; "function")]
+#[test_case(": f\n  1 + ;", false ; "function on multiple lines")]
+#[test_case(": f if 1", true ; "nested")]
+#[test_case("1 if 2", true ; "if block")]
+#[test_case("begin 1", true ; "begin loop")]
+#[test_case("10 0 do i", true ; "do loop")]
+#[test_case("( comment", true ; "comment")]
+#[test_case(".\" hello", true ; "string")]
+#[test_case("\\ comment", false ; "line comment")]
+#[test_case("1 2 +", false ; "complete")]
+#[test_case("1 variable", false ; "missing argument")]
+fn incomplete_input(code: &str, expected: bool) {
+    assert_eq!(Parser::is_incomplete(code), expected);
+}